@@ -0,0 +1,104 @@
+// Times the default log-space kernel, and the scalar and vectorised linear-space
+// kernels, over every internal node of a random tree for the test alignment. Run
+// with `cargo bench --bench kernel`
+use bactrees::genetic_data::*;
+use bactrees::newick_to_vec::random_vector;
+use bactrees::rate_matrix::{Gtr, RateMatrix};
+use bactrees::simd;
+use bactrees::site_rates::SiteRates;
+use bactrees::topology::from_vec;
+use nalgebra as na;
+use ndarray::s;
+use std::time::Instant;
+
+const ITERATIONS: usize = 20;
+
+// Runs a kernel for the children l and r with their transition matrices
+type NodeKernel<'a> = dyn Fn(usize, usize, &[na::Matrix4<f64>], &[na::Matrix4<f64>]) + 'a;
+
+fn main() {
+    let p = Gtr::default();
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let t = from_vec(&random_vector(28));
+    let gen_data = create_genetic_data(
+        "tests/test_files_in/listeria0.aln",
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Linear,
+        1,
+    );
+
+    // Children and transition matrices of each internal node
+    let nodes: Vec<_> = t
+        .postorder_notips(t.get_root())
+        .map(|node| {
+            let (l, r) = (node.get_lchild().unwrap(), node.get_rchild().unwrap());
+            let ml = transition_matrices(&p.get_matrix(), t.nodes[l].get_branchlen(), &rates);
+            let mr = transition_matrices(&p.get_matrix(), t.nodes[r].get_branchlen(), &rates);
+            (l, r, ml, mr)
+        })
+        .collect();
+    let child = |i: usize| {
+        (
+            gen_data.partials.slice(s![i, .., ..]),
+            gen_data.scalers.slice(s![i, ..]),
+        )
+    };
+
+    let time = |name: &str, kernel: &NodeKernel| {
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            for (l, r, ml, mr) in nodes.iter() {
+                kernel(*l, *r, ml, mr);
+            }
+        }
+        let secs = start.elapsed().as_secs_f64();
+        println!(
+            "{name}: {:.3} ms per tree ({} patterns x {} categories, {} nodes)",
+            1000.0 * secs / ITERATIONS as f64,
+            gen_data.n_patterns(),
+            rates.get_n_cats(),
+            nodes.len()
+        );
+        secs
+    };
+
+    // The default log-space kernel, which the linear-space kernels are compared to
+    let log_data = create_genetic_data(
+        "tests/test_files_in/listeria0.aln",
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Log,
+        1,
+    );
+    let log_child = |i: usize| log_data.partials.slice(s![i, .., ..]);
+    let log = time("log", &|l, r, ml, mr| {
+        std::hint::black_box(node_likelihood(log_child(l), log_child(r), ml, mr));
+    });
+
+    let scalar = time("scalar linear", &|l, r, ml, mr| {
+        std::hint::black_box(node_likelihood_scaled_scalar(child(l), child(r), ml, mr));
+    });
+    println!("speedup over log: {:.2}x", log / scalar);
+    if simd::available() {
+        let vector = time("simd linear", &|l, r, ml, mr| {
+            std::hint::black_box(simd::node_likelihood_scaled_simd(
+                child(l),
+                child(r),
+                ml,
+                mr,
+            ));
+        });
+        println!(
+            "speedup over log: {:.2}x, over scalar linear: {:.2}x",
+            log / vector,
+            scalar / vector
+        );
+    } else {
+        println!("simd linear: AVX and FMA not available on this CPU");
+    }
+}
@@ -0,0 +1,302 @@
+use crate::genetic_data::{child_likelihood_i, transition_matrices, GeneticData};
+use crate::rate_matrix::RateMatrix;
+use crate::site_rates::SiteRates;
+use crate::topology::Topology;
+use logaddexp::LogAddExp;
+use ndarray::{Array2, ArrayView2};
+use std::collections::HashMap;
+
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+// Marginal posterior probabilities of the four states at each internal node, with
+// one row per site pattern
+pub struct AncestralStates {
+    pub posteriors: HashMap<usize, Array2<f64>>,
+}
+
+impl AncestralStates {
+    // Internal node IDs in increasing order
+    pub fn nodes(&self) -> Vec<usize> {
+        let mut nodes: Vec<usize> = self.posteriors.keys().copied().collect();
+        nodes.sort();
+        nodes
+    }
+
+    // Most probable base at each pattern, the first in ACGT order on ties
+    pub fn sequence(&self, node: usize) -> Vec<u8> {
+        self.posteriors[&node]
+            .rows()
+            .into_iter()
+            .map(|row| {
+                let best = (0..4).fold(0, |best, j| if row[j] > row[best] { j } else { best });
+                BASES[best]
+            })
+            .collect()
+    }
+}
+
+// Log-probability of the data outside the subtree below each node, for each row of
+// the partials and state at the node. Calculated preorder from the root, where it is
+// the base frequencies
+fn outside_likelihoods<R: RateMatrix>(
+    top: &Topology,
+    gen_data: &GeneticData,
+    rate_matrix: &R,
+    site_rates: &SiteRates,
+) -> HashMap<usize, Array2<f64>> {
+    let root = top.get_root().get_id();
+    let n_rows = gen_data.n_rows();
+    let matrix = rate_matrix.get_matrix();
+    let log_freqs = rate_matrix.get_freqs().map(f64::ln);
+
+    let mut outside = HashMap::new();
+    outside.insert(
+        root,
+        Array2::from_shape_fn((n_rows, 4), |(_, j)| log_freqs[j]),
+    );
+    let mut stack = vec![root];
+    while let Some(parent) = stack.pop() {
+        let node = &top.nodes[parent];
+        let (Some(lchild), Some(rchild)) = (node.get_lchild(), node.get_rchild()) else {
+            continue;
+        };
+        for (child, sibling) in [(lchild, rchild), (rchild, lchild)] {
+            let p_child =
+                transition_matrices(&matrix, top.nodes[child].get_branchlen(), site_rates);
+            let p_sibling =
+                transition_matrices(&matrix, top.nodes[sibling].get_branchlen(), site_rates);
+            let above = above_branch(
+                outside[&parent].view(),
+                gen_data.log_partials(sibling).view(),
+                &p_sibling,
+            );
+            let child_outside = down_branch(above.view(), &p_child);
+            outside.insert(child, child_outside);
+            stack.push(child);
+        }
+    }
+    outside
+}
+
+// Log-probability of the data outside the subtree below a child, for each state at
+// the top of the branch to the child: the outside likelihood of the parent plus the
+// sibling's partials passed up the sibling's branch
+pub fn above_branch(
+    parent_outside: ArrayView2<f64>,
+    sibling_ll: ArrayView2<f64>,
+    p_sibling: &[na::Matrix4<f64>],
+) -> Array2<f64> {
+    let n_patterns = parent_outside.dim().0 / p_sibling.len();
+    Array2::from_shape_fn(parent_outside.dim(), |(i, x)| {
+        parent_outside[[i, x]]
+            + child_likelihood_i(x, sibling_ll.row(i), &p_sibling[i / n_patterns])
+    })
+}
+
+// Passes log-probabilities at the top of a branch down to the node below it
+pub fn down_branch(above: ArrayView2<f64>, p_child: &[na::Matrix4<f64>]) -> Array2<f64> {
+    let n_patterns = above.dim().0 / p_child.len();
+    Array2::from_shape_fn(above.dim(), |(i, y)| {
+        let p = &p_child[i / n_patterns];
+        (0..4)
+            .map(|x| above[[i, x]] + p[(x, y)].ln())
+            .reduce(|a, b| a.ln_add_exp(b))
+            .unwrap()
+    })
+}
+
+// Marginal reconstruction of the ancestral states at every internal node. The
+// posterior of each state combines the partials below the node with the outside
+// likelihood above it, summed over rate categories and the invariant class
+pub fn marginal_ancestral<R: RateMatrix>(
+    top: &Topology,
+    gen_data: &GeneticData,
+    rate_matrix: &R,
+    site_rates: &SiteRates,
+) -> AncestralStates {
+    let outside = outside_likelihoods(top, gen_data, rate_matrix, site_rates);
+    let n_patterns = gen_data.n_patterns();
+    let log_weights: Vec<f64> = site_rates.get_weights().iter().map(|w| w.ln()).collect();
+    let pinv = site_rates.get_pinv();
+    let log_freqs = rate_matrix.get_freqs().map(f64::ln);
+
+    let posteriors = top
+        .postorder_notips(top.get_root())
+        .map(|node| {
+            let id = node.get_id();
+            let inside = gen_data.log_partials(id);
+            let mut joint = Array2::from_shape_fn((n_patterns, 4), |(i, y)| {
+                let variable = log_weights
+                    .iter()
+                    .enumerate()
+                    .map(|(c, w)| {
+                        let row = c * n_patterns + i;
+                        w + inside[[row, y]] + outside[&id][[row, y]]
+                    })
+                    .reduce(|a, b| a.ln_add_exp(b))
+                    .unwrap();
+                if pinv > 0.0 {
+                    variable.ln_add_exp(pinv.ln() + log_freqs[y] + gen_data.invariant[[i, y]])
+                } else {
+                    variable
+                }
+            });
+            for mut row in joint.rows_mut() {
+                let total = row.iter().copied().reduce(|a, b| a.ln_add_exp(b)).unwrap();
+                row.mapv_inplace(|ll| (ll - total).exp());
+            }
+            (id, joint)
+        })
+        .collect();
+
+    AncestralStates { posteriors }
+}
+
+// A substitution at an alignment column (counted from zero) on the branch above a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mutation {
+    pub site: usize,
+    pub from: u8,
+    pub to: u8,
+}
+
+// Most probable joint assignment of bases to every node, including leaves, with one
+// base per site pattern
+pub struct JointStates {
+    pub states: Vec<Vec<u8>>,
+}
+
+impl JointStates {
+    // Substitutions on the branch above each node, keyed by the node ID, for every
+    // node except the root
+    pub fn branch_mutations(
+        &self,
+        top: &Topology,
+        gen_data: &GeneticData,
+    ) -> HashMap<usize, Vec<Mutation>> {
+        top.nodes
+            .iter()
+            .filter_map(|node| {
+                let parent = node.get_parent()?;
+                let (from, to) = (&self.states[parent], &self.states[node.get_id()]);
+                let mutations = gen_data
+                    .site_patterns
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, p)| from[**p] != to[**p])
+                    .map(|(site, p)| Mutation {
+                        site,
+                        from: from[*p],
+                        to: to[*p],
+                    })
+                    .collect();
+                Some((node.get_id(), mutations))
+            })
+            .collect()
+    }
+}
+
+// Joint reconstruction of the states at all nodes (Pupko et al. 2000). Each pattern
+// uses the transition matrices of its most probable rate category, as the algorithm
+// assumes a single rate. Going postorder, each node stores the best likelihood of its
+// subtree for every state of its parent, and the state at the node that achieves it.
+// The root state is then chosen and the stored states are read off preorder
+pub fn joint_ancestral<R: RateMatrix>(
+    top: &Topology,
+    gen_data: &GeneticData,
+    rate_matrix: &R,
+    site_rates: &SiteRates,
+) -> JointStates {
+    let n_patterns = gen_data.n_patterns();
+    let matrix = rate_matrix.get_matrix();
+    let log_freqs = rate_matrix.get_freqs().map(f64::ln);
+    let root = top.get_root().get_id();
+
+    // Posterior of each rate category at the root, up to a constant
+    let root_ll = gen_data.log_partials(root);
+    let log_weights: Vec<f64> = site_rates.get_weights().iter().map(|w| w.ln()).collect();
+    let best_cat: Vec<usize> = (0..n_patterns)
+        .map(|i| {
+            let cat_ll = |c: usize| {
+                (0..4)
+                    .map(|j| log_weights[c] + log_freqs[j] + root_ll[[c * n_patterns + i, j]])
+                    .reduce(|a, b| a.ln_add_exp(b))
+                    .unwrap()
+            };
+            (0..log_weights.len())
+                .max_by(|a, b| cat_ll(*a).total_cmp(&cat_ll(*b)))
+                .unwrap()
+        })
+        .collect();
+
+    // Log-likelihood of the best subtree below each node, by pattern and state
+    let mut subtree: HashMap<usize, Array2<f64>> = HashMap::new();
+    // For nodes other than the root, the best state by pattern and parent state
+    let mut best_state: HashMap<usize, Array2<usize>> = HashMap::new();
+    for node in top.postorder(top.get_root()) {
+        let id = node.get_id();
+        let node_ll = match (node.get_lchild(), node.get_rchild()) {
+            (Some(l), Some(r)) => &subtree[&l] + &subtree[&r],
+            _ => {
+                let leaf = gen_data.log_partials(id);
+                Array2::from_shape_fn((n_patterns, 4), |(i, j)| {
+                    leaf[[best_cat[i] * n_patterns + i, j]]
+                })
+            }
+        };
+        if id == root {
+            subtree.insert(id, node_ll);
+            continue;
+        }
+
+        let p = transition_matrices(&matrix, node.get_branchlen(), site_rates);
+        let mut ll = Array2::zeros((n_patterns, 4));
+        let mut states = Array2::zeros((n_patterns, 4));
+        for i in 0..n_patterns {
+            for x in 0..4 {
+                let score = |y: usize| p[best_cat[i]][(x, y)].ln() + node_ll[[i, y]];
+                let y = (0..4)
+                    .max_by(|a, b| score(*a).total_cmp(&score(*b)))
+                    .unwrap();
+                ll[[i, x]] = score(y);
+                states[[i, x]] = y;
+            }
+        }
+        subtree.insert(id, ll);
+        best_state.insert(id, states);
+    }
+
+    let mut states = vec![Vec::new(); top.nodes.len()];
+    states[root] = (0..n_patterns)
+        .map(|i| {
+            let score = |x: usize| log_freqs[x] + subtree[&root][[i, x]];
+            (0..4)
+                .max_by(|a, b| score(*a).total_cmp(&score(*b)))
+                .unwrap()
+        })
+        .collect::<Vec<usize>>();
+    let mut stack = vec![root];
+    while let Some(parent) = stack.pop() {
+        for child in [
+            top.nodes[parent].get_lchild(),
+            top.nodes[parent].get_rchild(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            states[child] = states[parent]
+                .iter()
+                .enumerate()
+                .map(|(i, x)| best_state[&child][[i, *x]])
+                .collect();
+            stack.push(child);
+        }
+    }
+
+    JointStates {
+        states: states
+            .iter()
+            .map(|s| s.iter().map(|x| BASES[*x]).collect())
+            .collect(),
+    }
+}
@@ -1,39 +1,354 @@
-// use crate::{rate_matrix::RateMatrix, treestate::TreeMove};
-// use rand::prelude::Distribution;
-// // use rand::distributions::Normal;
-// use statrs::distribution::Normal;
-// use crate::topology::Topology;
-// use crate::TreeState;
-// pub struct BranchMove{
-//     indices: Vec<usize>,
-// }
-
-// impl<R: RateMatrix> TreeMove<R> for BranchMove {
-//     fn generate(&self, ts: &crate::treestate::TreeState<R>) -> crate::treestate::TreeState<R> {
-//         let normal = Normal::new(0.0, 1.0).unwrap();
-//         let mut changes: Vec<usize> = Vec::new();
-
-//         // This is not ideal
-//         let mut nodes = ts.top.nodes.clone();
-
-//         for i in self.indices.iter() {
-//             let ind = *i;
-//             let mut bl = nodes[ind].get_branchlen();
-//             bl = bl.ln() + normal.sample(&mut rand::thread_rng());
-//             nodes[ind].set_branchlen(bl.exp());
-//             changes.push(ind);
-//         }
-
-//         let new_top = Topology{
-//             nodes: nodes,
-//             tree_vec: ts.top.tree_vec.clone(),
-//         };
-
-//         TreeState{
-//             top: new_top,
-//             mat: ts.mat,
-//             ll: ts.ll,
-//             changed_nodes: Some(changes),
-//         }
-//     }
-// }
\ No newline at end of file
+use crate::ancestral::{above_branch, down_branch};
+use crate::genetic_data::{
+    base_freq_logse, eigen_decomposition, transition_matrices, update_node, Ascertainment,
+    GeneticData,
+};
+use crate::moves::TreeMove;
+use crate::rate_matrix::{EigenDecomposition, RateMatrix};
+use crate::site_rates::SiteRates;
+use crate::topology::Topology;
+use crate::treestate::TreeState;
+use ndarray::{Array2, ArrayView1, ArrayView2};
+use rand::distributions::Distribution;
+use rand::seq::SliceRandom;
+use statrs::distribution::Normal;
+
+pub const MIN_BRANCH_LEN: f64 = 1e-8;
+pub const MAX_BRANCH_LEN: f64 = 10.0;
+
+// Exponentiated row of log-probabilities, divided by its largest value, and the log of
+// that value
+fn exp_row(row: ArrayView1<f64>) -> (na::Vector4<f64>, f64) {
+    let max = row.fold(f64::NEG_INFINITY, |a, b| a.max(*b));
+    (na::Vector4::from_fn(|j, _| (row[j] - max).exp()), max)
+}
+
+// Log-likelihood and its first and second derivatives with respect to the length of
+// one branch. above holds the log-probability of the data outside the subtree for
+// each state at the top of the branch, and below the log partials of the node under it
+pub fn branch_derivatives(
+    above: ArrayView2<f64>,
+    below: ArrayView2<f64>,
+    eigen: &EigenDecomposition,
+    branch_len: f64,
+    gen_data: &GeneticData,
+    site_rates: &SiteRates,
+    freqs: [f64; 4],
+) -> (f64, f64, f64) {
+    let n_patterns = gen_data.n_patterns();
+    let weights = site_rates.get_weights();
+    let pinv = site_rates.get_pinv();
+    // A rate category scales t, so each derivative gains a factor of the rate
+    let matrices: Vec<[na::Matrix4<f64>; 3]> = site_rates
+        .get_rates()
+        .iter()
+        .map(|r| {
+            let [p, d1, d2] = eigen.transition_derivatives(branch_len * r);
+            [p, d1 * *r, d2 * (r * r)]
+        })
+        .collect();
+
+    // Log-likelihood of each pattern, and its derivatives divided by the likelihood
+    let patterns: Vec<(f64, f64, f64)> = (0..n_patterns)
+        .map(|i| {
+            let rows: Vec<(f64, [f64; 3])> = matrices
+                .iter()
+                .enumerate()
+                .map(|(c, m)| {
+                    let row = c * n_patterns + i;
+                    let (a, max_a) = exp_row(above.row(row));
+                    let (b, max_b) = exp_row(below.row(row));
+                    (max_a + max_b, m.map(|m| a.dot(&(m * b))))
+                })
+                .collect();
+            let scale = rows.iter().fold(f64::NEG_INFINITY, |a, (s, _)| a.max(*s));
+            let mut lik = [0.0; 3];
+            for ((s, f), w) in rows.iter().zip(weights.iter()) {
+                for k in 0..3 {
+                    lik[k] += w * (s - scale).exp() * f[k];
+                }
+            }
+            if pinv > 0.0 {
+                let invariant = base_freq_logse(gen_data.invariant.row(i), freqs);
+                lik[0] += (pinv.ln() + invariant - scale).exp();
+            }
+            (lik[0].ln() + scale, lik[1] / lik[0], lik[2] / lik[0])
+        })
+        .collect();
+
+    let (mut ll, mut d1, mut d2) = (0.0, 0.0, 0.0);
+    for ((pattern_ll, r1, r2), w) in patterns.iter().zip(gen_data.weights.iter()) {
+        ll += w * pattern_ll;
+        d1 += w * r1;
+        d2 += w * (r2 - r1 * r1);
+    }
+
+    // Derivatives of the correction in ascertainment_correction
+    let constant = &patterns[n_patterns.saturating_sub(4)..];
+    // Total probability of a constant site and its derivatives, relative to the
+    // largest constant pattern
+    let max = constant.iter().fold(f64::NEG_INFINITY, |a, c| a.max(c.0));
+    let (p, p1, p2) = constant
+        .iter()
+        .fold((0.0, 0.0, 0.0), |acc, (c_ll, r1, r2)| {
+            let l = (c_ll - max).exp();
+            (acc.0 + l, acc.1 + l * r1, acc.2 + l * r2)
+        });
+    match gen_data.ascertainment {
+        Ascertainment::None => {}
+        Ascertainment::Lewis => {
+            let n_sites: f64 = gen_data.weights.iter().sum();
+            let (p, p1, p2) = (p * max.exp(), p1 * max.exp(), p2 * max.exp());
+            ll -= n_sites * (-p).ln_1p();
+            d1 += n_sites * p1 / (1.0 - p);
+            d2 += n_sites * (p2 * (1.0 - p) + p1 * p1) / ((1.0 - p) * (1.0 - p));
+        }
+        Ascertainment::Felsenstein(n_constant) => {
+            ll += n_constant * (max + p.ln());
+            d1 += n_constant * p1 / p;
+            d2 += n_constant * (p2 / p - (p1 / p) * (p1 / p));
+        }
+        Ascertainment::Stamatakis(counts) => {
+            for (n, (c_ll, r1, r2)) in counts.iter().zip(constant.iter()) {
+                ll += n * c_ll;
+                d1 += n * r1;
+                d2 += n * (r2 - r1 * r1);
+            }
+        }
+    }
+
+    (ll, d1, d2)
+}
+
+// Derivative of the log-likelihood with respect to the length of the branch above
+// each node, indexed by node and zero for the root. gen_data must hold the partials of
+// top. The data outside each subtree is passed down from the root, as in
+// optimise_subtree
+pub fn branch_gradient<R: RateMatrix>(
+    top: &Topology,
+    gen_data: &GeneticData,
+    rate_matrix: &R,
+    site_rates: &SiteRates,
+    eigen: &EigenDecomposition,
+) -> Vec<f64> {
+    let matrix = rate_matrix.get_matrix();
+    let freqs = rate_matrix.get_freqs();
+    let log_freqs = freqs.map(f64::ln);
+    let root = top.get_root().get_id();
+    let root_outside = Array2::from_shape_fn((gen_data.n_rows(), 4), |(_, j)| log_freqs[j]);
+
+    let mut gradient = vec![0.0; top.nodes.len()];
+    let mut stack = vec![(root, root_outside)];
+    while let Some((parent, outside)) = stack.pop() {
+        let node = &top.nodes[parent];
+        let (Some(lchild), Some(rchild)) = (node.get_lchild(), node.get_rchild()) else {
+            continue;
+        };
+        for (child, sibling) in [(lchild, rchild), (rchild, lchild)] {
+            let p_sibling =
+                transition_matrices(&matrix, top.nodes[sibling].get_branchlen(), site_rates);
+            let above = above_branch(
+                outside.view(),
+                gen_data.log_partials(sibling).view(),
+                &p_sibling,
+            );
+            let branch_len = top.nodes[child].get_branchlen();
+            let (_, d1, _) = branch_derivatives(
+                above.view(),
+                gen_data.log_partials(child).view(),
+                eigen,
+                branch_len,
+                gen_data,
+                site_rates,
+                freqs,
+            );
+            gradient[child] = d1;
+            if top.nodes[child].get_lchild().is_some() {
+                let p_child = transition_matrices(&matrix, branch_len, site_rates);
+                stack.push((child, down_branch(above.view(), &p_child)));
+            }
+        }
+    }
+    gradient
+}
+
+// Maximum likelihood length of one branch by Newton-Raphson from its current length.
+// Where the likelihood is not concave the length is doubled or halved instead, and
+// steps that lower the likelihood are shortened
+pub fn optimise_branch(
+    above: ArrayView2<f64>,
+    below: ArrayView2<f64>,
+    eigen: &EigenDecomposition,
+    branch_len: f64,
+    gen_data: &GeneticData,
+    site_rates: &SiteRates,
+    freqs: [f64; 4],
+) -> f64 {
+    let derivatives =
+        |t: f64| branch_derivatives(above, below, eigen, t, gen_data, site_rates, freqs);
+    let mut t = branch_len.clamp(MIN_BRANCH_LEN, MAX_BRANCH_LEN);
+    let (mut ll, mut d1, mut d2) = derivatives(t);
+
+    for _ in 0..50 {
+        let target = if d2 < 0.0 {
+            t - d1 / d2
+        } else if d1 > 0.0 {
+            2.0 * t
+        } else {
+            0.5 * t
+        };
+        let mut new_t = target.clamp(MIN_BRANCH_LEN, MAX_BRANCH_LEN);
+        let mut new = derivatives(new_t);
+        for _ in 0..20 {
+            if new.0 >= ll {
+                break;
+            }
+            new_t = 0.5 * (t + new_t);
+            new = derivatives(new_t);
+        }
+        if new.0 < ll {
+            break;
+        }
+
+        let step = (new_t - t).abs();
+        t = new_t;
+        (ll, d1, d2) = new;
+        if step < 1e-6 * t.max(1e-3) {
+            break;
+        }
+    }
+    t
+}
+
+// Optimises the branches below node, given the log-probability of the data outside
+// its subtree, then recalculates its partials
+fn optimise_subtree<R: RateMatrix>(
+    ts: &mut TreeState<R>,
+    gen_data: &mut GeneticData,
+    node: usize,
+    outside: ArrayView2<f64>,
+    eigen: &EigenDecomposition,
+) {
+    let (Some(lchild), Some(rchild)) = (
+        ts.top.nodes[node].get_lchild(),
+        ts.top.nodes[node].get_rchild(),
+    ) else {
+        return;
+    };
+    let matrix = ts.mat.get_matrix();
+
+    for (child, sibling) in [(lchild, rchild), (rchild, lchild)] {
+        let p_sibling =
+            transition_matrices(&matrix, ts.top.nodes[sibling].get_branchlen(), &ts.rates);
+        let above = above_branch(outside, gen_data.log_partials(sibling).view(), &p_sibling);
+        let branch_len = optimise_branch(
+            above.view(),
+            gen_data.log_partials(child).view(),
+            eigen,
+            ts.top.nodes[child].get_branchlen(),
+            gen_data,
+            &ts.rates,
+            ts.mat.get_freqs(),
+        );
+        ts.top.nodes[child].set_branchlen(branch_len);
+
+        let p_child = transition_matrices(&matrix, branch_len, &ts.rates);
+        let child_outside = down_branch(above.view(), &p_child);
+        optimise_subtree(ts, gen_data, child, child_outside.view(), eigen);
+    }
+
+    update_node(gen_data, &ts.top, node, &matrix, &ts.rates);
+}
+
+// Maximum likelihood branch lengths for the topology of ts, optimising each branch in
+// turn until a round over the whole tree improves the log-likelihood by less than
+// tolerance. Branches are visited preorder so the data outside each subtree is passed
+// down from its parent, and partials are recalculated on the way back up. gen_data
+// must hold the partials of ts, and is updated to the new branch lengths. The rate
+// matrix must be reversible
+pub fn optimise_branch_lengths<R: RateMatrix>(
+    mut ts: TreeState<R>,
+    gen_data: &mut GeneticData,
+    tolerance: f64,
+) -> TreeState<R> {
+    let eigen = eigen_decomposition(&ts.mat.get_matrix())
+        .expect("Optimising branch lengths needs a reversible rate matrix");
+    let log_freqs = ts.mat.get_freqs().map(f64::ln);
+    let root_outside = Array2::from_shape_fn((gen_data.n_rows(), 4), |(_, j)| log_freqs[j]);
+    let root = ts.top.get_root().get_id();
+
+    ts.likelihood = ts.top.likelihood(gen_data, &ts.mat, &ts.rates);
+    for _ in 0..100 {
+        optimise_subtree(&mut ts, gen_data, root, root_outside.view(), &eigen);
+        let ll = ts.top.likelihood(gen_data, &ts.mat, &ts.rates);
+        let improvement = ll - ts.likelihood;
+        ts.likelihood = ll;
+        if improvement < tolerance {
+            break;
+        }
+    }
+    ts
+}
+
+// Multiplies the length of the branch above each node in indices by a log-normal
+// factor exp(N(0, sigma^2)), keeping it within the allowed range. The root has no
+// branch. Only the parents of the changed nodes and their ancestors need new
+// partials. The proposal is not symmetric: an MCMC acceptance would need the product
+// of new over old lengths as the Hastings ratio
+pub struct BranchMove {
+    pub indices: Vec<usize>,
+    pub sigma: f64,
+}
+
+impl<R: RateMatrix> TreeMove<R> for BranchMove {
+    fn generate(&self, ts: &TreeState<R>) -> (Option<Topology>, Option<R>, Option<Vec<usize>>) {
+        let normal = Normal::new(0.0, self.sigma).unwrap();
+        let mut rng = rand::thread_rng();
+        let mut new_topology = ts.top.clone();
+        let mut changes: Vec<usize> = Vec::new();
+
+        for i in self.indices.iter() {
+            let Some(parent) = ts.top.nodes[*i].get_parent() else {
+                continue;
+            };
+            let bl = ts.top.nodes[*i].get_branchlen() * normal.sample(&mut rng).exp();
+            new_topology.nodes[*i].set_branchlen(bl.clamp(MIN_BRANCH_LEN, MAX_BRANCH_LEN));
+            changes.push(parent);
+        }
+
+        changes.sort();
+        changes.dedup();
+        if changes.is_empty() {
+            (None, None, None)
+        } else {
+            (Some(new_topology), None, Some(changes))
+        }
+    }
+}
+
+// BranchMove on n different branches chosen uniformly at random
+pub struct RandomBranchMove {
+    pub n: usize,
+    pub sigma: f64,
+}
+
+impl<R: RateMatrix> TreeMove<R> for RandomBranchMove {
+    fn generate(&self, ts: &TreeState<R>) -> (Option<Topology>, Option<R>, Option<Vec<usize>>) {
+        let branches: Vec<usize> = ts
+            .top
+            .nodes
+            .iter()
+            .filter(|node| node.get_parent().is_some())
+            .map(|node| node.get_id())
+            .collect();
+        let indices = branches
+            .choose_multiple(&mut rand::thread_rng(), self.n)
+            .copied()
+            .collect();
+        let mv = BranchMove {
+            indices,
+            sigma: self.sigma,
+        };
+        mv.generate(ts)
+    }
+}
@@ -1,5 +1,9 @@
 
-use clap::Parser;
+use crate::distance::DistanceMethod;
+use crate::genetic_data::{Ascertainment, Kernel};
+use crate::optimise::Optimiser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -8,12 +12,221 @@ pub struct Args {
     #[arg(short, long, default_value = "tests/test_files_in/listeria0.aln")]
     pub alignment: String,
 
+    /// Starting tree in Newick format, with leaves named as in the alignment (built
+    /// with --start-tree if not given)
+    #[arg(short, long)]
+    pub tree: Option<String>,
+
+    /// How to build the starting tree when --tree is not given
+    #[arg(long, value_enum, default_value_t = StartTree::Bionj)]
+    pub start_tree: StartTree,
+
+    /// Distance between sequences used by neighbour-joining starting trees
+    #[arg(long, value_enum, default_value_t = DistanceMethod::K2p)]
+    pub distance: DistanceMethod,
+
+    /// Nucleotide substitution model
+    #[arg(short, long, value_enum, default_value_t = Model::Gtr)]
+    pub model: Model,
+
+    /// Fit every substitution model on the starting tree and use the best one
+    #[arg(long, default_value_t = false)]
+    pub model_select: bool,
+
+    /// Also try +I, +G and +G+I in model selection, with --gamma-cats categories (4 if not given)
+    #[arg(long, default_value_t = false)]
+    pub select_rate_het: bool,
+
+    /// Information criterion used to choose the best model
+    #[arg(long, value_enum, default_value_t = Criterion::Bic)]
+    pub criterion: Criterion,
+
     /// Write the likelihood of the tree and alignment, do not optimise
     #[arg(long, default_value_t = false)]
     pub no_optimise: bool,
+
+    /// Use base frequencies counted from the alignment rather than equal frequencies.
+    /// Needs a model with free base frequencies
+    #[arg(long, default_value_t = false)]
+    pub empirical_freqs: bool,
+
+    /// Number of discrete gamma rate categories (1 for no rate heterogeneity)
+    #[arg(long, default_value_t = 1)]
+    pub gamma_cats: usize,
+
+    /// Shape parameter alpha of the gamma distribution of rates across sites
+    #[arg(long, default_value_t = 1.0)]
+    pub alpha: f64,
+
+    /// Proportion of invariant sites (+I)
+    #[arg(long, default_value_t = 0.0, value_parser = parse_pinv)]
+    pub pinv: f64,
+
+    /// Likelihood kernel, with partials stored in log space or scaled linear space.
+    /// linear is the fastest, and linear-f32 halves the memory used by the partials
+    #[arg(long, value_enum, default_value_t = Kernel::Log)]
+    pub kernel: Kernel,
+
+    /// Number of threads to share the sites of the alignment between
+    #[arg(long, default_value_t = 1)]
+    pub threads: usize,
+
+    /// Write the log-likelihood of each site of the final tree to this file, in
+    /// TREE-PUZZLE/RAxML .sitelh format
+    #[arg(long)]
+    pub site_lh: Option<String>,
+
+    /// Reconstruct the marginal ancestral states at internal nodes of the final tree,
+    /// writing the most probable sequences to <PREFIX>.fasta and the probabilities
+    /// of each state to <PREFIX>.state
+    #[arg(long, value_name = "PREFIX")]
+    pub ancestral: Option<String>,
+
+    /// Map substitutions onto branches with a joint ancestral reconstruction of the
+    /// final tree, writing the site, original and new base of each to this file
+    #[arg(long)]
+    pub mutations: Option<String>,
+
+    /// Write the final tree to this file, with the number of substitutions mapped
+    /// onto each branch in a [&snps=N] comment
+    #[arg(long)]
+    pub snp_tree: Option<String>,
+
+    /// Write the sites where a base arose more than once on the final tree to this
+    /// file, with the branches of each substitution and the consistency index
+    #[arg(long)]
+    pub homoplasy: Option<String>,
+
+    /// After the search, jointly optimise the rate matrix, gamma alpha and branch
+    /// lengths of the final tree with this optimiser
+    #[arg(long, value_enum)]
+    pub optimise_params: Option<Optimiser>,
+
+    /// Maximum number of iterations of --optimise-params
+    #[arg(long, default_value_t = 100)]
+    pub max_iters: u64,
+
+    /// Ascertainment bias correction for alignments without constant sites
+    #[arg(long, value_enum)]
+    pub asc: Option<AscCorrection>,
+
+    /// Numbers of constant A,C,G,T sites removed from the alignment
+    #[arg(
+        long,
+        value_parser = parse_fconst,
+        required_if_eq_any([("asc", "felsenstein"), ("asc", "stamatakis")])
+    )]
+    pub fconst: Option<[f64; 4]>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    /// Jukes-Cantor, equal rates and base frequencies
+    Jc69,
+    /// Kimura 2-parameter, transitions and transversions
+    K80,
+    /// Felsenstein 81, equal rates with base frequencies
+    F81,
+    /// Hasegawa-Kishino-Yano, K80 with base frequencies
+    Hky85,
+    /// Tamura-Nei, separate purine and pyrimidine transitions
+    Tn93,
+    /// Transition model, two transversion rates and two transition rates
+    Tim,
+    /// Transversion model, four transversion rates and one transition rate
+    Tvm,
+    /// Symmetric, GTR rates with equal base frequencies
+    Sym,
+    /// General time-reversible
+    Gtr,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum StartTree {
+    /// Random tree vector, with all branch lengths one
+    Random,
+    /// Neighbour-joining
+    Nj,
+    /// BIONJ, neighbour-joining weighted by the variance of the distances
+    Bionj,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Criterion {
+    /// Akaike information criterion
+    Aic,
+    /// AIC with small sample size correction
+    Aicc,
+    /// Bayesian information criterion
+    Bic,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum AscCorrection {
+    /// Condition on all sites being variable
+    Lewis,
+    /// Use the total number of removed constant sites from --fconst
+    Felsenstein,
+    /// Use the number of removed constant sites of each base from --fconst
+    Stamatakis,
+}
+
+// Four comma separated counts, so a malformed --fconst is a usage error
+fn parse_fconst(s: &str) -> Result<[f64; 4], String> {
+    let counts = s
+        .split(',')
+        .map(|c| c.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|e| e.to_string())?;
+    match counts[..] {
+        [a, c, g, t] if counts.iter().all(|n| *n >= 0.0) => Ok([a, c, g, t]),
+        [_, _, _, _] => Err(String::from("counts cannot be negative")),
+        _ => Err(String::from(
+            "needs four comma separated counts for A,C,G,T",
+        )),
+    }
+}
+
+// A proportion in [0, 1), as every site cannot be invariant
+fn parse_pinv(s: &str) -> Result<f64, String> {
+    let pinv = s.trim().parse::<f64>().map_err(|e| e.to_string())?;
+    if (0.0..1.0).contains(&pinv) {
+        Ok(pinv)
+    } else {
+        Err(String::from("must be at least 0 and less than 1"))
+    }
+}
+
+impl Args {
+    /// Ascertainment correction from the command line, where giving only --fconst
+    /// is the same as adding those constant sites back to the alignment
+    pub fn ascertainment(&self) -> Ascertainment {
+        match (self.asc, self.fconst) {
+            (None, None) => Ascertainment::None,
+            (Some(AscCorrection::Lewis), _) => Ascertainment::Lewis,
+            (Some(AscCorrection::Felsenstein), Some(c)) => {
+                Ascertainment::Felsenstein(c.iter().sum())
+            }
+            (Some(AscCorrection::Stamatakis) | None, Some(c)) => Ascertainment::Stamatakis(c),
+            (Some(_), None) => unreachable!("clap requires --fconst for this correction"),
+        }
+    }
+
+    /// Checks combinations of arguments that cannot be declared to clap
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        if self.asc == Some(AscCorrection::Lewis) && self.fconst.is_some() {
+            return Err(Args::command().error(
+                ErrorKind::ArgumentConflict,
+                "--fconst cannot be used with --asc lewis, which assumes no constant sites",
+            ));
+        }
+        Ok(())
+    }
 }
 
 /// Function to parse command line args into [`Args`] struct
 pub fn cli_args() -> Args {
-    Args::parse()
+    let args = Args::parse();
+    args.validate().unwrap_or_else(|e| e.exit());
+    args
 }
@@ -0,0 +1,192 @@
+use crate::branchlength::MAX_BRANCH_LEN;
+use crate::newick::NewickError;
+use crate::topology::{topology_from_nodes, NodeTuple, Topology};
+use ndarray::Array2;
+
+// Corrections of the proportion of differing sites between two sequences
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum DistanceMethod {
+    /// Proportion of sites that differ, uncorrected
+    PDistance,
+    /// Jukes-Cantor corrected distance
+    Jc69,
+    /// Kimura 2-parameter distance, correcting transitions and transversions separately
+    K2p,
+}
+
+fn base_index(base: u8) -> Option<usize> {
+    match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' | b'U' => Some(3),
+        _ => None,
+    }
+}
+
+// Distance between two sequences, counted over the sites where both have an
+// unambiguous base. Saturated or incomparable pairs get the longest branch length
+pub fn pairwise_distance(a: &[u8], b: &[u8], method: DistanceMethod) -> f64 {
+    let (mut n_sites, mut transitions, mut transversions) = (0, 0, 0);
+    for (x, y) in a.iter().zip(b.iter()) {
+        if let (Some(x), Some(y)) = (base_index(*x), base_index(*y)) {
+            n_sites += 1;
+            if x != y {
+                // A<->G and C<->T differ by two in ACGT order
+                if x.abs_diff(y) == 2 {
+                    transitions += 1;
+                } else {
+                    transversions += 1;
+                }
+            }
+        }
+    }
+    if n_sites == 0 {
+        return MAX_BRANCH_LEN;
+    }
+    let p = transitions as f64 / n_sites as f64;
+    let q = transversions as f64 / n_sites as f64;
+    let d = match method {
+        DistanceMethod::PDistance => p + q,
+        DistanceMethod::Jc69 => -0.75 * (1.0 - 4.0 / 3.0 * (p + q)).ln(),
+        DistanceMethod::K2p => -0.5 * (1.0 - 2.0 * p - q).ln() - 0.25 * (1.0 - 2.0 * q).ln(),
+    };
+    match d.is_finite() {
+        true => d.min(MAX_BRANCH_LEN),
+        false => MAX_BRANCH_LEN,
+    }
+}
+
+// Distances between every pair of sequences
+pub fn distance_matrix(seqs: &[Vec<u8>], method: DistanceMethod) -> Array2<f64> {
+    let n = seqs.len();
+    let mut dist = Array2::zeros((n, n));
+    for i in 0..n {
+        for j in (i + 1)..n {
+            let d = pairwise_distance(&seqs[i], &seqs[j], method);
+            dist[[i, j]] = d;
+            dist[[j, i]] = d;
+        }
+    }
+    dist
+}
+
+// Neighbour-joining tree of a distance matrix (Saitou and Nei 1987), or with bionj the
+// BIONJ variant (Gascuel 1997), which weights the distances to each new node by their
+// variances. Leaves are named by row. The tree is unrooted, so the last three
+// clusters are joined at the root, and negative branch lengths are set to zero
+pub fn neighbour_joining(
+    dist: &Array2<f64>,
+    names: &[String],
+    bionj: bool,
+) -> Result<Topology, NewickError> {
+    let n = dist.nrows();
+    if n < 2 {
+        return Err(NewickError {
+            offset: 0,
+            message: String::from("Need at least two sequences to build a tree"),
+        });
+    }
+    let mut d = dist.clone();
+    // Variances of the distances, estimated by the distances themselves in BIONJ
+    let mut v = dist.clone();
+    // Leaves are the first n nodes, and each join adds a node above two clusters
+    let mut nodes: Vec<NodeTuple> = (0..n)
+        .map(|i| NodeTuple(i, None, None, None, 1.0, 0))
+        .collect();
+    // Node at the top of the subtree in each row of d that is still to be joined
+    let mut clusters: Vec<usize> = (0..n).collect();
+    let mut active: Vec<usize> = (0..n).collect();
+
+    while active.len() > 3 {
+        let m = active.len() as f64;
+        let r: Vec<f64> = active
+            .iter()
+            .map(|i| active.iter().map(|k| d[[*i, *k]]).sum())
+            .collect();
+
+        let (mut best, mut min_q) = ((0, 1), f64::INFINITY);
+        for a in 0..active.len() {
+            for b in (a + 1)..active.len() {
+                let q = (m - 2.0) * d[[active[a], active[b]]] - r[a] - r[b];
+                if q < min_q {
+                    (best, min_q) = ((a, b), q);
+                }
+            }
+        }
+        let (a, b) = best;
+        let (i, j) = (active[a], active[b]);
+        let dij = d[[i, j]];
+        let bl_i = 0.5 * dij + (r[a] - r[b]) / (2.0 * (m - 2.0));
+        let bl_j = dij - bl_i;
+
+        // Weight of i in the distances from the new node
+        let lambda = match bionj && v[[i, j]] > 0.0 {
+            true => {
+                let diff: f64 = active.iter().map(|k| v[[j, *k]] - v[[i, *k]]).sum();
+                (0.5 + diff / (2.0 * (m - 2.0) * v[[i, j]])).clamp(0.0, 1.0)
+            }
+            false => 0.5,
+        };
+
+        // The new node takes the row of i
+        for k in active.iter().copied().filter(|k| *k != i && *k != j) {
+            let new_d = match bionj {
+                true => lambda * (d[[i, k]] - bl_i) + (1.0 - lambda) * (d[[j, k]] - bl_j),
+                false => 0.5 * (d[[i, k]] + d[[j, k]] - dij),
+            };
+            let new_v = lambda * v[[i, k]] + (1.0 - lambda) * v[[j, k]]
+                - lambda * (1.0 - lambda) * v[[i, j]];
+            d[[i, k]] = new_d;
+            d[[k, i]] = new_d;
+            v[[i, k]] = new_v;
+            v[[k, i]] = new_v;
+        }
+        clusters[i] = join(
+            &mut nodes,
+            (clusters[i], bl_i.max(0.0)),
+            (clusters[j], bl_j.max(0.0)),
+        );
+        active.remove(b);
+    }
+
+    match active[..] {
+        [i, j] => {
+            let bl = 0.5 * d[[i, j]];
+            join(&mut nodes, (clusters[i], bl), (clusters[j], bl));
+        }
+        [i, j, k] => {
+            let bl =
+                |x: usize, y: usize, z: usize| (0.5 * (d[[x, y]] + d[[x, z]] - d[[y, z]])).max(0.0);
+            // The root is binary, so two of the three are joined below it by a branch
+            // of length zero
+            let below = join(
+                &mut nodes,
+                (clusters[i], bl(i, j, k)),
+                (clusters[j], bl(j, i, k)),
+            );
+            join(&mut nodes, (below, 0.0), (clusters[k], bl(k, i, j)));
+        }
+        _ => unreachable!(),
+    }
+    // Every node was added after its children, so parents are set before children
+    for id in (0..nodes.len()).rev() {
+        if let Some(parent) = nodes[id].get_parent() {
+            let depth = nodes[parent].get_depth() + 1;
+            nodes[id].set_depth(depth);
+        }
+    }
+    topology_from_nodes(nodes, names)
+}
+
+// Adds a node above the subtrees a and b, each given with the length of the branch
+// above it, returning the ID of the new node
+fn join(nodes: &mut Vec<NodeTuple>, a: (usize, f64), b: (usize, f64)) -> usize {
+    let id = nodes.len();
+    for (child, bl) in [a, b] {
+        nodes[child].set_parent(Some(id));
+        nodes[child].set_branchlen(bl);
+    }
+    nodes.push(NodeTuple(id, None, Some(a.0), Some(b.0), 1.0, 0));
+    id
+}
@@ -1,10 +1,18 @@
+use crate::rate_matrix::EigenDecomposition;
+use crate::simd::node_likelihood_scaled_simd;
+use crate::site_rates::SiteRates;
 use crate::topology::Topology;
+use cached::proc_macro::cached;
 use logaddexp::LogAddExp;
 use ndarray::s;
 use needletail::parse_fastx_file;
 use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use std::collections::HashMap;
+use std::f64::consts::LN_2;
 use std::os::unix::thread;
+use std::sync::Arc;
 use std::thread::current;
 
 const NEGINF: f64 = -f64::INFINITY;
@@ -55,89 +63,495 @@ pub fn count_sequences(filename: &str) -> usize {
     n_seqs
 }
 
-pub fn create_genetic_data(
-    filename: &str,
-    topology: &Topology,
-    rate_matrix: &na::Matrix4<f64>,
-) -> ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 3]>> {
-    // Count number of sequences and their length
-    let mut n_seqs = 0;
-    let mut n_bases = 0;
+// Partial likelihoods are stored with one block of site patterns per rate category,
+// so row c * n_patterns + i holds pattern i under rate category c
+#[derive(Debug, Clone)]
+pub struct GeneticData {
+    pub partials: ndarray::Array3<f64>,
+    // Single precision partials, used instead of partials by the LinearF32 kernel
+    pub partials_f32: ndarray::Array3<f32>,
+    // Power of two scaling each row of the partials, summed over the subtree below
+    // the node. Empty with the log-space kernel, which does not scale
+    pub scalers: ndarray::Array2<i32>,
+    pub kernel: Kernel,
+    // Threads sharing the sites when calculating each node, shared between clones.
+    // None calculates each node on the calling thread
+    pub pool: Option<Arc<ThreadPool>>,
+    // Log-likelihood of each pattern if every leaf had the same state, used by +I
+    pub invariant: ndarray::Array2<f64>,
+    // Number of alignment columns with each pattern
+    pub weights: Vec<f64>,
+    // Pattern index of each original alignment column
+    pub site_patterns: Vec<usize>,
+    // With a correction the last four patterns are constant A, C, G and T columns
+    pub ascertainment: Ascertainment,
+}
+
+// Ascertainment bias correction for alignments of variable sites only
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ascertainment {
+    None,
+    // Condition the likelihood on every site being variable (Lewis 2001)
+    Lewis,
+    // Include a known total number of removed constant sites (Felsenstein 1992)
+    Felsenstein(f64),
+    // Include known numbers of removed constant A, C, G and T sites (Stamatakis 2011)
+    Stamatakis([f64; 4]),
+}
+
+// How partial likelihoods are stored and combined at internal nodes
+#[derive(Debug, Clone, Copy, PartialEq, Default, clap::ValueEnum)]
+pub enum Kernel {
+    /// Log-space partials, summed with ln_add_exp. Not vectorised, and much slower
+    /// than the linear-space kernels
+    #[default]
+    Log,
+    /// Linear-space partials, rescaled at each node and site to avoid underflow. The
+    /// fast path, vectorised with AVX where the CPU supports it
+    Linear,
+    /// Linear-space partials stored in single precision, calculated in double
+    LinearF32,
+}
+
+// Rows of linear partials with a largest value below this are rescaled
+pub const SCALING_THRESHOLD: f64 = 2.938_735_877_055_719e-39; // 2^-128
+
+// Types linear partials are stored in. Each node is calculated in double precision
+// and rounded when stored
+pub trait Partial: Copy + Default + Send + Sync + Into<f64> {
+    // Rows with a largest value below this are rescaled
+    const SCALING_THRESHOLD: f64;
+    fn from_f64(p: f64) -> Self;
+}
+
+impl Partial for f64 {
+    const SCALING_THRESHOLD: f64 = SCALING_THRESHOLD;
+    fn from_f64(p: f64) -> Self {
+        p
+    }
+}
+
+// Rescaled earlier, so rows stay well inside the normal range of f32
+impl Partial for f32 {
+    const SCALING_THRESHOLD: f64 = 5.421_010_862_427_522e-20; // 2^-64
+    fn from_f64(p: f64) -> Self {
+        p as f32
+    }
+}
+
+// Partials of one node in the precision they are stored in
+#[derive(Debug, Clone)]
+pub enum NodePartials {
+    Double(ndarray::Array2<f64>),
+    Single(ndarray::Array2<f32>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum NodeView<'a> {
+    Double(ndarray::ArrayView2<'a, f64>),
+    Single(ndarray::ArrayView2<'a, f32>),
+}
+
+impl NodePartials {
+    pub fn view(&self) -> NodeView<'_> {
+        match self {
+            NodePartials::Double(partials) => NodeView::Double(partials.view()),
+            NodePartials::Single(partials) => NodeView::Single(partials.view()),
+        }
+    }
+}
+
+impl GeneticData {
+    // Leaf partials must already be filled in (in log space), internal nodes are
+    // calculated later. Each pattern starts with weight one, matching a single
+    // alignment column
+    pub fn new(partials: ndarray::Array3<f64>, n_leaves: usize, n_cats: usize) -> Self {
+        let n_patterns = partials.dim().1 / n_cats;
+        let invariant = ndarray::Array2::from_shape_fn((n_patterns, 4), |(i, j)| {
+            (0..n_leaves).fold(0.0, |acc, leaf| acc + partials[[leaf, i, j]])
+        });
+        GeneticData {
+            scalers: ndarray::Array2::zeros((0, 0)),
+            kernel: Kernel::Log,
+            pool: None,
+            partials,
+            partials_f32: ndarray::Array3::zeros((0, 0, 4)),
+            invariant,
+            weights: vec![1.0; n_patterns],
+            site_patterns: (0..n_patterns).collect(),
+            ascertainment: Ascertainment::None,
+        }
+    }
+
+    // Leaf partials for the columns of each pattern, stored for the kernel. Single
+    // precision partials are filled in directly, without a double precision copy
+    pub fn from_patterns(
+        patterns: &[Vec<u8>],
+        n_seqs: usize,
+        n_cats: usize,
+        kernel: Kernel,
+    ) -> Self {
+        let n_patterns = patterns.len();
+        let shape = (2 * n_seqs - 1, n_cats * n_patterns, 4);
+        // Log-likelihood of each state at a leaf, or zero for internal nodes, which
+        // are calculated later
+        let leaf_ll = |(node, row, j): (usize, usize, usize)| match node < n_seqs {
+            true => char_to_likelihood(&(patterns[row % n_patterns][node] as char))[j],
+            false => 0.0,
+        };
+
+        let mut data = GeneticData {
+            scalers: ndarray::Array2::zeros((0, 0)),
+            kernel,
+            pool: None,
+            partials: ndarray::Array3::zeros((0, 0, 4)),
+            partials_f32: ndarray::Array3::zeros((0, 0, 4)),
+            invariant: ndarray::Array2::from_shape_fn((n_patterns, 4), |(i, j)| {
+                (0..n_seqs).fold(0.0, |acc, leaf| acc + leaf_ll((leaf, i, j)))
+            }),
+            weights: vec![1.0; n_patterns],
+            site_patterns: (0..n_patterns).collect(),
+            ascertainment: Ascertainment::None,
+        };
+        if kernel != Kernel::Log {
+            data.scalers = ndarray::Array2::zeros((shape.0, shape.1));
+        }
+        match kernel {
+            Kernel::Log => data.partials = ndarray::Array3::from_shape_fn(shape, leaf_ll),
+            Kernel::Linear => {
+                data.partials = ndarray::Array3::from_shape_fn(shape, |i| leaf_ll(i).exp())
+            }
+            Kernel::LinearF32 => {
+                data.partials_f32 =
+                    ndarray::Array3::from_shape_fn(shape, |i| leaf_ll(i).exp() as f32)
+            }
+        }
+        data
+    }
+
+    pub fn n_patterns(&self) -> usize {
+        self.invariant.dim().0
+    }
+
+    pub fn n_sites(&self) -> usize {
+        self.site_patterns.len()
+    }
+
+    // Rows of partials at each node, one per pattern in each rate category
+    pub fn n_rows(&self) -> usize {
+        match self.kernel {
+            Kernel::LinearF32 => self.partials_f32.dim().1,
+            _ => self.partials.dim().1,
+        }
+    }
+
+    // Starts the pool of threads used for every later node calculation
+    pub fn set_threads(&mut self, threads: usize) {
+        self.pool = match threads {
+            0 | 1 => None,
+            n => Some(Arc::new(
+                ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .build()
+                    .expect("Could not start thread pool"),
+            )),
+        };
+    }
+
+    // Converts all the partials to another kernel. Internal nodes should be
+    // recalculated afterwards, as the log-space partials may have underflowed
+    pub fn set_kernel(&mut self, kernel: Kernel) {
+        if kernel == self.kernel {
+            return;
+        }
+        if self.kernel != Kernel::Log {
+            let (n_nodes, n_rows) = self.scalers.dim();
+            let mut log_partials = ndarray::Array3::zeros((n_nodes, n_rows, 4));
+            for node in 0..n_nodes {
+                log_partials
+                    .slice_mut(s![node, .., ..])
+                    .assign(&self.log_partials(node));
+            }
+            self.partials = log_partials;
+            self.partials_f32 = ndarray::Array3::zeros((0, 0, 4));
+        }
+        let (n_nodes, n_rows, _) = self.partials.dim();
+        match kernel {
+            Kernel::Log => {}
+            Kernel::Linear => self.partials.mapv_inplace(f64::exp),
+            Kernel::LinearF32 => {
+                self.partials_f32 = self.partials.mapv(|ll| ll.exp() as f32);
+                self.partials = ndarray::Array3::zeros((0, 0, 4));
+            }
+        }
+        self.scalers = match kernel {
+            Kernel::Log => ndarray::Array2::zeros((0, 0)),
+            Kernel::Linear | Kernel::LinearF32 => ndarray::Array2::zeros((n_nodes, n_rows)),
+        };
+        self.kernel = kernel;
+    }
+
+    // Partials of a node as stored
+    pub fn node_view(&self, node: usize) -> NodeView<'_> {
+        match self.kernel {
+            Kernel::LinearF32 => NodeView::Single(self.partials_f32.slice(s![node, .., ..])),
+            _ => NodeView::Double(self.partials.slice(s![node, .., ..])),
+        }
+    }
+
+    // Scalers of a node, empty with the log-space kernel
+    pub fn node_scalers(&self, node: usize) -> ndarray::ArrayView1<'_, i32> {
+        match self.kernel {
+            Kernel::Log => ndarray::ArrayView1::from(&[]),
+            Kernel::Linear | Kernel::LinearF32 => self.scalers.row(node),
+        }
+    }
+
+    // Stores the partials and scalers calculated for a node
+    pub fn set_node(
+        &mut self,
+        node: usize,
+        partials: &NodePartials,
+        scalers: &ndarray::Array1<i32>,
+    ) {
+        match partials {
+            NodePartials::Double(partials) => {
+                self.partials.slice_mut(s![node, .., ..]).assign(partials)
+            }
+            NodePartials::Single(partials) => self
+                .partials_f32
+                .slice_mut(s![node, .., ..])
+                .assign(partials),
+        }
+        if self.kernel != Kernel::Log {
+            self.scalers.row_mut(node).assign(scalers);
+        }
+    }
+
+    // Partials of a node in log space, whichever kernel is used
+    pub fn log_partials(&self, node: usize) -> ndarray::Array2<f64> {
+        node_log_partials(self.kernel, self.node_view(node), self.node_scalers(node))
+    }
+
+    // Map a value for each pattern back to each original alignment column
+    pub fn expand_patterns<T: Copy>(&self, pattern_values: &[T]) -> Vec<T> {
+        self.site_patterns
+            .iter()
+            .map(|p| pattern_values[*p])
+            .collect()
+    }
+}
+
+// Reads every sequence of an alignment into memory, with its taxon name: the ID of
+// the record up to the first space
+pub fn read_alignment(filename: &str) -> (Vec<String>, Vec<Vec<u8>>) {
     let mut reader = parse_fastx_file(filename).expect("Error parsing file");
+    let mut names: Vec<String> = Vec::new();
+    let mut seqs: Vec<Vec<u8>> = Vec::new();
     while let Some(record) = reader.next() {
         let seqrec = record.expect("Invalid record");
-        n_seqs += 1;
-        n_bases = seqrec.num_bases();
+        let id = String::from_utf8_lossy(seqrec.id());
+        names.push(id.split_whitespace().next().unwrap_or_default().to_string());
+        seqs.push(seqrec.seq().to_vec());
     }
-    // Create pre-filled array
-    let mut gen_data: ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 3]>> =
-        ndarray::Array3::from_elem((2 * n_seqs - 1, n_bases, 4), -99.0);
-    // println!("Assigning data for {} leaves and {} total nodes", n_seqs, (2 * n_seqs) + 1);
+    (names, seqs)
+}
 
-    let mut reader2 = parse_fastx_file(filename).expect("Error parsing file");
-    let mut seq_i = 0;
-    while let Some(record) = reader2.next() {
-        let seqrec = record.expect("Invalid record");
-        for (loc_i, e) in seqrec.seq().iter().enumerate() {
-            let cur = char_to_likelihood(&(*e as char));
-            for j in 0..4 {
-                gen_data[[seq_i, loc_i, j]] = *cur.get(j).unwrap();
+// Base frequencies counted from the alignment, with ambiguous bases shared
+// between the states they could be and gaps ignored
+pub fn empirical_base_freqs(seqs: &[Vec<u8>]) -> [f64; 4] {
+    let mut counts = [0.0; 4];
+    for e in seqs.iter().flatten() {
+        let cur = char_to_likelihood(&(*e as char));
+        let n_states = cur.iter().filter(|l| **l == 0.0).count();
+        if n_states < 4 {
+            for (count, l) in counts.iter_mut().zip(cur.iter()) {
+                if *l == 0.0 {
+                    *count += 1.0 / n_states as f64;
+                }
             }
         }
-        seq_i += 1;
     }
+    let total: f64 = counts.iter().sum();
+    counts.map(|c| c / total)
+}
 
-    create_internal_data(gen_data, topology, rate_matrix)
+// Collapses identical alignment columns, returning each unique column, the number
+// of times it occurs and the pattern index of every original column
+pub fn compress_patterns(seqs: &[Vec<u8>]) -> (Vec<Vec<u8>>, Vec<f64>, Vec<usize>) {
+    let n_bases = seqs[0].len();
+    let mut pattern_index: HashMap<Vec<u8>, usize> = HashMap::new();
+    let mut patterns: Vec<Vec<u8>> = Vec::new();
+    let mut weights: Vec<f64> = Vec::new();
+    let mut site_patterns: Vec<usize> = Vec::with_capacity(n_bases);
+
+    for loc_i in 0..n_bases {
+        let column: Vec<u8> = seqs.iter().map(|seq| seq[loc_i]).collect();
+        let p = match pattern_index.get(&column) {
+            Some(p) => *p,
+            None => {
+                let p = patterns.len();
+                pattern_index.insert(column.clone(), p);
+                patterns.push(column);
+                weights.push(0.0);
+                p
+            }
+        };
+        weights[p] += 1.0;
+        site_patterns.push(p);
+    }
+
+    (patterns, weights, site_patterns)
+}
+
+// Whether some base is compatible with every sequence at column loc_i, so that the
+// column could be constant
+pub fn is_constant_site(seqs: &[Vec<u8>], loc_i: usize) -> bool {
+    (0..4).any(|j| {
+        seqs.iter()
+            .all(|seq| char_to_likelihood(&(seq[loc_i] as char))[j] == 0.0)
+    })
+}
+
+pub fn create_genetic_data(
+    filename: &str,
+    topology: &Topology,
+    rate_matrix: &na::Matrix4<f64>,
+    site_rates: &SiteRates,
+    ascertainment: Ascertainment,
+    kernel: Kernel,
+    threads: usize,
+) -> GeneticData {
+    let (_, seqs) = read_alignment(filename);
+    create_genetic_data_from_seqs(
+        &seqs,
+        topology,
+        rate_matrix,
+        site_rates,
+        ascertainment,
+        kernel,
+        threads,
+    )
+}
+
+// As create_genetic_data, for an alignment already read into memory
+pub fn create_genetic_data_from_seqs(
+    seqs: &[Vec<u8>],
+    topology: &Topology,
+    rate_matrix: &na::Matrix4<f64>,
+    site_rates: &SiteRates,
+    ascertainment: Ascertainment,
+    kernel: Kernel,
+    threads: usize,
+) -> GeneticData {
+    let n_seqs = seqs.len();
+    let (mut patterns, mut weights, site_patterns) = compress_patterns(seqs);
+    let n_variable = patterns.len();
+    // Constant patterns are only needed to calculate the correction, so have no weight
+    if ascertainment != Ascertainment::None {
+        for base in [b'A', b'C', b'G', b'T'] {
+            patterns.push(vec![base; n_seqs]);
+            weights.push(0.0);
+        }
+    }
+    let mut data = GeneticData::from_patterns(&patterns, n_seqs, site_rates.get_n_cats(), kernel);
+    data.weights = weights;
+    data.site_patterns = site_patterns;
+    data.ascertainment = ascertainment;
+
+    if ascertainment != Ascertainment::None {
+        let n_constant = (0..n_variable)
+            .filter(|i| data.invariant.row(*i).iter().any(|ll| *ll == 0.0))
+            .count();
+        if n_constant > 0 {
+            panic!(
+                "Ascertainment bias correction needs only variable sites, found {} constant site patterns",
+                n_constant
+            );
+        }
+    }
+
+    data.set_threads(threads);
+    create_internal_data(data, topology, rate_matrix, site_rates)
 }
 
 pub fn create_internal_data(
-    mut data: ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 3]>>,
+    mut data: GeneticData,
     topology: &Topology,
     rate_matrix: &na::Matrix4<f64>,
-) -> ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 3]>> {
+    site_rates: &SiteRates,
+) -> GeneticData {
     // Iterate over internal nodes postorder
     let nodes = topology.postorder_notips(topology.get_root());
 
     for node in nodes {
-        let i = node.get_id();
-        // Calculate node likelihood
-        let lchild = node.get_lchild().unwrap();
-        let rchild = node.get_rchild().unwrap();
-        let node_ll = node_likelihood(
-            data.slice(s![lchild, .., ..]),
-            data.slice(s![rchild, .., ..]),
-            &matrix_exp(rate_matrix, topology.nodes[lchild].get_branchlen()),
-            &matrix_exp(rate_matrix, topology.nodes[lchild].get_branchlen()),
-        );
-        // let node_ll = node_likelihood(node.get_lchild().unwrap(), node.get_rchild().unwrap(), &gen_data, topology, rate_matrix);
-        // let node_ll = node_likelihood(i, &gen_data, topology, rate_matrix);
-        // Add to genetic data array
-        data.slice_mut(s![i, .., ..]).assign(&node_ll);
+        update_node(&mut data, topology, node.get_id(), rate_matrix, site_rates);
     }
 
     data
 }
 
+// Recalculates the partials of one internal node from its children
+pub fn update_node(
+    data: &mut GeneticData,
+    topology: &Topology,
+    i: usize,
+    rate_matrix: &na::Matrix4<f64>,
+    site_rates: &SiteRates,
+) {
+    let node = &topology.nodes[i];
+    // Calculate node likelihood
+    let lchild = node.get_lchild().unwrap();
+    let rchild = node.get_rchild().unwrap();
+    let (node_ll, node_scalers) = node_partials(
+        data.kernel,
+        data.pool.as_deref(),
+        (data.node_view(lchild), data.node_scalers(lchild)),
+        (data.node_view(rchild), data.node_scalers(rchild)),
+        &transition_matrices(
+            rate_matrix,
+            topology.nodes[lchild].get_branchlen(),
+            site_rates,
+        ),
+        &transition_matrices(
+            rate_matrix,
+            topology.nodes[rchild].get_branchlen(),
+            site_rates,
+        ),
+    );
+    // Add to genetic data array
+    data.set_node(i, &node_ll, &node_scalers);
+}
+
 pub fn create_dummy_gendata(
     n_bases: usize,
     topology: &Topology,
     rate_matrix: &na::Matrix4<f64>,
-) -> ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 3]>> {
+    site_rates: &SiteRates,
+) -> GeneticData {
     let n_seqs = topology.count_leaves();
+    let n_cats = site_rates.get_n_cats();
 
     let mut gen_data: ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 3]>> =
-        ndarray::Array3::from_elem(((2 * n_seqs) + 1, n_bases, 4), 0.0);
+        ndarray::Array3::from_elem(((2 * n_seqs) + 1, n_cats * n_bases, 4), 0.0);
 
     let mut rng = thread_rng();
 
     for i in 0..n_seqs {
         for j in 0..n_bases {
             let k = rng.gen_range(0..4);
-            gen_data[[i, j, k]] = NEGINF;
+            for c in 0..n_cats {
+                gen_data[[i, c * n_bases + j, k]] = NEGINF;
+            }
         }
     }
 
-    create_internal_data(gen_data, topology, rate_matrix)
+    create_internal_data(
+        GeneticData::new(gen_data, n_seqs, n_cats),
+        topology,
+        rate_matrix,
+        site_rates,
+    )
 }
 
 pub fn child_likelihood_i(
@@ -153,22 +567,286 @@ pub fn child_likelihood_i(
         .unwrap()
 }
 
+// Bit patterns of Q, to key the caches
+fn matrix_key(rate_matrix: &na::Matrix4<f64>) -> [u64; 16] {
+    let mut key = [0; 16];
+    for (k, q) in key.iter_mut().zip(rate_matrix.iter()) {
+        *k = q.to_bits();
+    }
+    key
+}
+
+// Q only changes with the model parameters, so is diagonalised once for many branches
+#[cached(
+    size = 64,
+    key = "[u64; 16]",
+    convert = r#"{ matrix_key(rate_matrix) }"#
+)]
+pub fn eigen_decomposition(rate_matrix: &na::Matrix4<f64>) -> Option<EigenDecomposition> {
+    EigenDecomposition::new(rate_matrix)
+}
+
+// P(t) for a branch, kept in an LRU cache keyed by Q and the branch length.
+// Falls back to the matrix exponential when Q is not reversible
+#[cached(
+    size = 4096,
+    key = "([u64; 16], u64)",
+    convert = r#"{ (matrix_key(rate_matrix), branch_len.to_bits()) }"#
+)]
 pub fn matrix_exp(rate_matrix: &na::Matrix4<f64>, branch_len: f64) -> na::Matrix4<f64> {
-    na::Matrix::exp(&(rate_matrix * branch_len))
+    match eigen_decomposition(rate_matrix) {
+        Some(eigen) => eigen.transition_matrix(branch_len),
+        None => na::Matrix::exp(&(rate_matrix * branch_len)),
+    }
 }
 
+// One transition probability matrix per rate category for a branch
+pub fn transition_matrices(
+    rate_matrix: &na::Matrix4<f64>,
+    branch_len: f64,
+    site_rates: &SiteRates,
+) -> Vec<na::Matrix4<f64>> {
+    site_rates
+        .get_rates()
+        .iter()
+        .map(|r| matrix_exp(rate_matrix, branch_len * r))
+        .collect()
+}
+
+// Rows are split evenly between rate categories, each using its own matrix
 pub fn node_likelihood(
     seql: ndarray::ArrayBase<ndarray::ViewRepr<&f64>, ndarray::Dim<[usize; 2]>>,
     seqr: ndarray::ArrayBase<ndarray::ViewRepr<&f64>, ndarray::Dim<[usize; 2]>>,
-    matrixl: &na::Matrix4<f64>,
-    matrixr: &na::Matrix4<f64>,
+    matrixl: &[na::Matrix4<f64>],
+    matrixr: &[na::Matrix4<f64>],
 ) -> ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 2]>> {
-    let out = ndarray::Array2::from_shape_fn((seql.dim().0, 4), |(i, j)| {
-        child_likelihood_i(j, seql.slice(s![i, ..]), matrixl)
-            + child_likelihood_i(j, seqr.slice(s![i, ..]), matrixr)
+    let n_sites = seql.dim().0 / matrixl.len();
+
+    ndarray::Array2::from_shape_fn((seql.dim().0, 4), |(i, j)| {
+        let c = i / n_sites;
+        child_likelihood_i(j, seql.slice(s![i, ..]), &matrixl[c])
+            + child_likelihood_i(j, seqr.slice(s![i, ..]), &matrixr[c])
+    })
+}
+
+// Linear-space version of node_likelihood. Each row is multiplied by a power of two
+// when its largest value gets small, which is exact, adding the exponent of the
+// factor to the children's scalers.
+// Uses the vectorised kernel when the CPU supports it
+pub fn node_likelihood_scaled(
+    left: (ndarray::ArrayView2<f64>, ndarray::ArrayView1<i32>),
+    right: (ndarray::ArrayView2<f64>, ndarray::ArrayView1<i32>),
+    matrixl: &[na::Matrix4<f64>],
+    matrixr: &[na::Matrix4<f64>],
+) -> (ndarray::Array2<f64>, ndarray::Array1<i32>) {
+    node_likelihood_scaled_simd(left, right, matrixl, matrixr)
+        .unwrap_or_else(|| node_likelihood_scaled_scalar(left, right, matrixl, matrixr))
+}
+
+pub fn node_likelihood_scaled_scalar<T: Partial>(
+    left: (ndarray::ArrayView2<T>, ndarray::ArrayView1<i32>),
+    right: (ndarray::ArrayView2<T>, ndarray::ArrayView1<i32>),
+    matrixl: &[na::Matrix4<f64>],
+    matrixr: &[na::Matrix4<f64>],
+) -> (ndarray::Array2<T>, ndarray::Array1<i32>) {
+    let (seql, scalel) = left;
+    let (seqr, scaler) = right;
+    let n_rows = seql.dim().0;
+    let n_sites = n_rows / matrixl.len();
+    let mut partials = ndarray::Array2::default((n_rows, 4));
+    let mut scalers = ndarray::Array1::zeros(n_rows);
+
+    for i in 0..n_rows {
+        let c = i / n_sites;
+        let l = na::Vector4::from_iterator(seql.row(i).iter().map(|p| (*p).into()));
+        let r = na::Vector4::from_iterator(seqr.row(i).iter().map(|p| (*p).into()));
+        let mut node = (matrixl[c] * l).component_mul(&(matrixr[c] * r));
+        let mut scale = scalel[i] + scaler[i];
+        let max = node.max();
+        if max > 0.0 && max < T::SCALING_THRESHOLD {
+            let exponent = scaling_exponent(max);
+            node *= 2f64.powi(-exponent);
+            scale += exponent;
+        }
+        for (p, n) in partials.row_mut(i).iter_mut().zip(node.iter()) {
+            *p = T::from_f64(*n);
+        }
+        scalers[i] = scale;
+    }
+
+    (partials, scalers)
+}
+
+// Power of two that brings the largest value of a row to [1, 2) when divided out.
+// Kept in the range where two to its negative is finite
+pub fn scaling_exponent(max: f64) -> i32 {
+    (max.log2().floor() as i32).max(f64::MIN_EXP - 1)
+}
+
+fn kernel_partials(
+    kernel: Kernel,
+    left: (ndarray::ArrayView2<f64>, ndarray::ArrayView1<i32>),
+    right: (ndarray::ArrayView2<f64>, ndarray::ArrayView1<i32>),
+    matrixl: &[na::Matrix4<f64>],
+    matrixr: &[na::Matrix4<f64>],
+) -> (ndarray::Array2<f64>, ndarray::Array1<i32>) {
+    match kernel {
+        Kernel::Log => (
+            node_likelihood(left.0, right.0, matrixl, matrixr),
+            ndarray::Array1::zeros(0),
+        ),
+        Kernel::Linear | Kernel::LinearF32 => node_likelihood_scaled(left, right, matrixl, matrixr),
+    }
+}
+
+// Partials of a node from the partials and scalers of its two children, with the
+// chosen kernel. Single precision children are read directly by the scalar kernel,
+// and its result stored in single precision
+pub fn node_partials(
+    kernel: Kernel,
+    pool: Option<&ThreadPool>,
+    left: (NodeView, ndarray::ArrayView1<i32>),
+    right: (NodeView, ndarray::ArrayView1<i32>),
+    matrixl: &[na::Matrix4<f64>],
+    matrixr: &[na::Matrix4<f64>],
+) -> (NodePartials, ndarray::Array1<i32>) {
+    match (left.0, right.0) {
+        (NodeView::Double(seql), NodeView::Double(seqr)) => {
+            let (partials, scalers) = split_sites(
+                pool,
+                (seql, left.1),
+                (seqr, right.1),
+                matrixl,
+                matrixr,
+                |l, r, ml, mr| kernel_partials(kernel, l, r, ml, mr),
+            );
+            (NodePartials::Double(partials), scalers)
+        }
+        (NodeView::Single(seql), NodeView::Single(seqr)) => {
+            let (partials, scalers) = split_sites(
+                pool,
+                (seql, left.1),
+                (seqr, right.1),
+                matrixl,
+                matrixr,
+                node_likelihood_scaled_scalar,
+            );
+            (NodePartials::Single(partials), scalers)
+        }
+        _ => panic!("Children of a node are stored in different precisions"),
+    }
+}
+
+// Runs a kernel over all the sites of a node. With a thread pool each thread gets a
+// contiguous block of sites in every rate category. Sites are independent, so the
+// result does not depend on the number of threads. Children without scalers give a
+// node without scalers
+fn split_sites<T, F>(
+    pool: Option<&ThreadPool>,
+    left: (ndarray::ArrayView2<T>, ndarray::ArrayView1<i32>),
+    right: (ndarray::ArrayView2<T>, ndarray::ArrayView1<i32>),
+    matrixl: &[na::Matrix4<f64>],
+    matrixr: &[na::Matrix4<f64>],
+    kernel: F,
+) -> (ndarray::Array2<T>, ndarray::Array1<i32>)
+where
+    T: Partial,
+    F: Fn(
+            (ndarray::ArrayView2<T>, ndarray::ArrayView1<i32>),
+            (ndarray::ArrayView2<T>, ndarray::ArrayView1<i32>),
+            &[na::Matrix4<f64>],
+            &[na::Matrix4<f64>],
+        ) -> (ndarray::Array2<T>, ndarray::Array1<i32>)
+        + Sync,
+{
+    let n_rows = left.0.dim().0;
+    let n_cats = matrixl.len();
+    let n_sites = n_rows / n_cats;
+    let threads = pool.map_or(1, ThreadPool::current_num_threads);
+    let pool = match pool {
+        Some(pool) if threads > 1 && n_sites >= threads => pool,
+        _ => return kernel(left, right, matrixl, matrixr),
+    };
+
+    let scaled = !left.1.is_empty();
+    let chunk = n_sites.div_ceil(threads);
+    let blocks: Vec<(usize, usize)> = (0..n_sites)
+        .step_by(chunk)
+        .map(|start| (start, (start + chunk).min(n_sites)))
+        .collect();
+    let results: Vec<Vec<_>> = pool.install(|| {
+        blocks
+            .par_iter()
+            .map(|&(start, end)| {
+                (0..n_cats)
+                    .map(|c| {
+                        let (first, last) = (c * n_sites + start, c * n_sites + end);
+                        kernel(
+                            (
+                                left.0.slice(s![first..last, ..]),
+                                scaler_rows(left.1, first, last),
+                            ),
+                            (
+                                right.0.slice(s![first..last, ..]),
+                                scaler_rows(right.1, first, last),
+                            ),
+                            &matrixl[c..=c],
+                            &matrixr[c..=c],
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
     });
 
-    out
+    let mut partials = ndarray::Array2::default((n_rows, 4));
+    let mut scalers = ndarray::Array1::zeros(if scaled { n_rows } else { 0 });
+    for (block_results, &(start, end)) in results.into_iter().zip(blocks.iter()) {
+        for (c, (block, block_scalers)) in block_results.into_iter().enumerate() {
+            let (first, last) = (c * n_sites + start, c * n_sites + end);
+            partials.slice_mut(s![first..last, ..]).assign(&block);
+            if scaled {
+                scalers.slice_mut(s![first..last]).assign(&block_scalers);
+            }
+        }
+    }
+
+    (partials, scalers)
+}
+
+// Rows first..last of the scalers of a node, which are empty without scaling
+fn scaler_rows(
+    scalers: ndarray::ArrayView1<'_, i32>,
+    first: usize,
+    last: usize,
+) -> ndarray::ArrayView1<'_, i32> {
+    match scalers.is_empty() {
+        true => scalers,
+        false => scalers.slice_move(s![first..last]),
+    }
+}
+
+// Log-space partials of a node from its linear partials and power of two scalers
+pub fn to_log_space<T: Partial>(
+    partials: ndarray::ArrayView2<T>,
+    scalers: ndarray::ArrayView1<i32>,
+) -> ndarray::Array2<f64> {
+    ndarray::Array2::from_shape_fn(partials.dim(), |(i, j)| {
+        partials[[i, j]].into().ln() + scalers[i] as f64 * LN_2
+    })
+}
+
+// Log-space partials of a node as calculated by a kernel
+pub fn node_log_partials(
+    kernel: Kernel,
+    partials: NodeView,
+    scalers: ndarray::ArrayView1<i32>,
+) -> ndarray::Array2<f64> {
+    match partials {
+        NodeView::Double(partials) if kernel == Kernel::Log => partials.to_owned(),
+        NodeView::Double(partials) => to_log_space(partials, scalers),
+        NodeView::Single(partials) => to_log_space(partials, scalers),
+    }
 }
 
 pub const BF_DEFAULT: [f64; 4] = [0.25, 0.25, 0.25, 0.25];
@@ -177,12 +855,91 @@ pub fn base_freq_logse(
     muta: ndarray::ArrayBase<ndarray::ViewRepr<&f64>, ndarray::Dim<[usize; 1]>>,
     bf: [f64; 4],
 ) -> f64 {
-    muta.iter()
+    // Factor out the largest term so very small partials do not underflow
+    let max = muta.fold(NEGINF, |a, b| a.max(*b));
+    if max == NEGINF {
+        return NEGINF;
+    }
+    max + muta
+        .iter()
         .zip(bf.iter())
-        .fold(0.0, |tot, (muta, bf)| tot + muta.exp() * bf)
+        .fold(0.0, |tot, (muta, bf)| tot + (muta - max).exp() * bf)
         .ln()
 }
 
+// Log-likelihood of each site pattern from the partial likelihoods at the root,
+// averaging each pattern over the rate categories and the invariant class
+pub fn pattern_likelihoods(
+    root: ndarray::ArrayBase<ndarray::ViewRepr<&f64>, ndarray::Dim<[usize; 2]>>,
+    gen_data: &GeneticData,
+    bf: [f64; 4],
+    site_rates: &SiteRates,
+) -> Vec<f64> {
+    let weights = site_rates.get_weights();
+    let pinv = site_rates.get_pinv();
+    let n_patterns = gen_data.n_patterns();
+
+    (0..n_patterns)
+        .map(|i| {
+            let variable = weights
+                .iter()
+                .enumerate()
+                .map(|(c, w)| w.ln() + base_freq_logse(root.row(c * n_patterns + i), bf))
+                .reduce(|a, b| a.ln_add_exp(b))
+                .unwrap();
+
+            if pinv > 0.0 {
+                variable.ln_add_exp(pinv.ln() + base_freq_logse(gen_data.invariant.row(i), bf))
+            } else {
+                variable
+            }
+        })
+        .collect()
+}
+
+// Log-likelihood of the whole alignment, weighting each pattern by its count
+pub fn root_likelihood(
+    root: ndarray::ArrayBase<ndarray::ViewRepr<&f64>, ndarray::Dim<[usize; 2]>>,
+    gen_data: &GeneticData,
+    bf: [f64; 4],
+    site_rates: &SiteRates,
+) -> f64 {
+    let pattern_ll = pattern_likelihoods(root, gen_data, bf, site_rates);
+
+    pattern_ll
+        .iter()
+        .zip(gen_data.weights.iter())
+        .fold(0.0, |acc, (ll, w)| acc + w * ll)
+        + ascertainment_correction(&pattern_ll, gen_data)
+}
+
+// Term added to the log-likelihood of the variable sites, using the likelihoods
+// of the four constant patterns at the end of the data
+pub fn ascertainment_correction(pattern_ll: &[f64], gen_data: &GeneticData) -> f64 {
+    let constant_ll = &pattern_ll[pattern_ll.len().saturating_sub(4)..];
+
+    match gen_data.ascertainment {
+        Ascertainment::None => 0.0,
+        Ascertainment::Lewis => {
+            let p_constant: f64 = constant_ll.iter().map(|ll| ll.exp()).sum();
+            let n_sites: f64 = gen_data.weights.iter().sum();
+            -n_sites * (-p_constant).ln_1p()
+        }
+        Ascertainment::Felsenstein(n_constant) => {
+            n_constant
+                * constant_ll
+                    .iter()
+                    .copied()
+                    .reduce(|a, b| a.ln_add_exp(b))
+                    .unwrap()
+        }
+        Ascertainment::Stamatakis(counts) => counts
+            .iter()
+            .zip(constant_ll.iter())
+            .fold(0.0, |acc, (n, ll)| acc + n * ll),
+    }
+}
+
 impl Topology {
     pub fn find_changes(&self, other: &Topology) -> Option<Vec<usize>> {
         let out: Vec<usize> = self
@@ -0,0 +1,86 @@
+use crate::ancestral::{joint_ancestral, Mutation};
+use crate::genetic_data::GeneticData;
+use crate::rate_matrix::RateMatrix;
+use crate::treestate::TreeState;
+use std::collections::HashMap;
+
+// Substitutions mapped onto the tree at one alignment column (counted from zero)
+#[derive(Debug, Clone)]
+pub struct SiteHomoplasy {
+    pub site: usize,
+    // Branches with a substitution, given by the node below, and the substitution
+    pub branches: Vec<(usize, Mutation)>,
+    // Fewest substitutions that explain the bases seen in the leaves
+    pub min_changes: usize,
+}
+
+impl SiteHomoplasy {
+    pub fn n_changes(&self) -> usize {
+        self.branches.len()
+    }
+
+    // Minimum over mapped number of substitutions, one when there is no homoplasy
+    pub fn consistency_index(&self) -> f64 {
+        self.min_changes as f64 / self.n_changes() as f64
+    }
+
+    // Some base arose more than once, by convergence or reversal
+    pub fn is_homoplastic(&self) -> bool {
+        self.n_changes() > self.min_changes
+    }
+}
+
+// Every site with a substitution on the tree of ts, mapped with a joint ancestral
+// reconstruction. Only unambiguous bases in the leaves count towards the minimum
+// number of changes
+pub fn homoplasy_report<R: RateMatrix>(
+    ts: &TreeState<R>,
+    gen_data: &GeneticData,
+) -> Vec<SiteHomoplasy> {
+    let states = joint_ancestral(&ts.top, gen_data, &ts.mat, &ts.rates);
+    let mut by_site: HashMap<usize, Vec<(usize, Mutation)>> = HashMap::new();
+    for (node, mutations) in states.branch_mutations(&ts.top, gen_data) {
+        for m in mutations {
+            by_site.entry(m.site).or_default().push((node, m));
+        }
+    }
+
+    // Bases seen at each pattern, as a bit per base
+    let n_leaves = ts.top.count_leaves();
+    let mut observed = vec![0_u8; gen_data.n_patterns()];
+    for leaf in 0..n_leaves {
+        let leaf_ll = gen_data.log_partials(leaf);
+        for (i, bases) in observed.iter_mut().enumerate() {
+            let possible: Vec<usize> = (0..4).filter(|j| leaf_ll[[i, *j]] == 0.0).collect();
+            if let [base] = possible[..] {
+                *bases |= 1 << base;
+            }
+        }
+    }
+
+    let mut report: Vec<SiteHomoplasy> = by_site
+        .into_iter()
+        .map(|(site, mut branches)| {
+            branches.sort_by_key(|(node, _)| *node);
+            let bases = observed[gen_data.site_patterns[site]];
+            SiteHomoplasy {
+                site,
+                branches,
+                min_changes: (bases.count_ones() as usize).saturating_sub(1),
+            }
+        })
+        .collect();
+    report.sort_by_key(|s| s.site);
+    report
+}
+
+// Consistency index of the whole alignment, summing the minimum and mapped numbers
+// of substitutions over sites. Undefined when no substitutions were mapped
+pub fn ensemble_consistency_index(report: &[SiteHomoplasy]) -> Option<f64> {
+    let min_changes: usize = report.iter().map(|s| s.min_changes).sum();
+    let n_changes: usize = report.iter().map(|s| s.n_changes()).sum();
+    match n_changes {
+        0 => None,
+        n => Some(min_changes as f64 / n as f64),
+    }
+}
@@ -1,12 +1,22 @@
+mod ancestral;
 mod branchlength;
-mod genetic_data;
+mod distance;
+pub mod genetic_data;
+mod homoplasy;
 mod iterators;
+mod model_select;
 mod moves;
-mod newick_to_vec;
-mod rate_matrix;
+pub mod newick;
+pub mod newick_to_vec;
+mod optimise;
+mod output;
+pub mod rate_matrix;
+pub mod simd;
+pub mod site_rates;
 mod state_data;
+#[cfg(test)]
 mod tests;
-mod topology;
+pub mod topology;
 mod treestate;
 
 use rate_matrix::RateMatrix;
@@ -18,11 +28,22 @@ use rand::distributions::Distribution;
 use crate::newick_to_vec::*;
 extern crate nalgebra as na;
 pub mod cli;
+use crate::ancestral::{joint_ancestral, marginal_ancestral};
+use crate::branchlength::{optimise_branch_lengths, RandomBranchMove};
 use crate::cli::*;
+use crate::distance::{distance_matrix, neighbour_joining};
 use crate::genetic_data::*;
+use crate::homoplasy::{ensemble_consistency_index, homoplasy_report};
+use crate::model_select::*;
 use crate::moves::*;
-use crate::topology::from_vec;
+use crate::optimise::{optimise_parameters, FreeParams};
+use crate::output::*;
+use crate::rate_matrix::MatrixMove;
+use crate::site_rates::SiteRates;
 use crate::topology::NodeTuple;
+use crate::topology::{from_vec, topology_from_newick};
+use clap::error::ErrorKind;
+use clap::CommandFactory;
 use ndarray::s;
 use std::time::Instant;
 
@@ -34,20 +55,119 @@ pub fn main() {
     // tr.add_genetic_data(&String::from("/Users/joel/Downloads/listeria0.aln"));
     // let n_seqs = count_sequences(&args.alignment);
 
-    let tree_vec: Vec<usize> = random_vector(28);
+    let (names, seqs) = read_alignment(&args.alignment);
+    if args.ascertainment() != Ascertainment::None {
+        let n_constant = (0..seqs[0].len())
+            .filter(|i| is_constant_site(&seqs, *i))
+            .count();
+        if n_constant > 0 {
+            Args::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "--asc and --fconst need an alignment of variable sites only, found {} constant columns",
+                        n_constant
+                    ),
+                )
+                .exit();
+        }
+    }
+    let mut t: Topology = match &args.tree {
+        Some(filename) => {
+            let newick = std::fs::read_to_string(filename).expect("Could not read tree");
+            topology_from_newick(&newick, &names)
+                .unwrap_or_else(|e| panic!("Could not read tree {}: {}", filename, e))
+        }
+        None => match args.start_tree {
+            StartTree::Random => from_vec(&random_vector(names.len())),
+            StartTree::Nj | StartTree::Bionj => {
+                let dist = distance_matrix(&seqs, args.distance);
+                neighbour_joining(&dist, &names, args.start_tree == StartTree::Bionj)
+                    .unwrap_or_else(|e| panic!("Could not build starting tree: {}", e))
+            }
+        },
+    };
+    t.names = names;
 
-    let t: Topology = from_vec(&tree_vec);
+    let fit = if args.model_select {
+        let rate_het_cats = match args.gamma_cats {
+            1 => 4,
+            n => n,
+        };
+        let fits = select_model(
+            &seqs,
+            &t,
+            args.ascertainment(),
+            args.kernel,
+            args.threads,
+            args.select_rate_het.then_some(rate_het_cats),
+            args.criterion,
+        );
+        print_model_table(&fits);
+        eprintln!("Best model by {:?}: {}", args.criterion, fits[0].name);
+        // Start the search from the branch lengths fitted with the best model
+        for node in t.nodes.iter_mut() {
+            node.set_branchlen(node.get_branchlen() * fits[0].tree_scale);
+        }
+        Some(fits[0].clone())
+    } else {
+        None
+    };
+
+    match fit.as_ref().map_or(args.model, |f| f.model) {
+        Model::Jc69 => run(&args, &seqs, t, rate_matrix::Jc69::default(), fit),
+        Model::K80 => run(&args, &seqs, t, rate_matrix::K80::default(), fit),
+        Model::F81 => run(&args, &seqs, t, rate_matrix::F81::default(), fit),
+        Model::Hky85 => run(&args, &seqs, t, rate_matrix::Hky85::default(), fit),
+        Model::Tn93 => run(&args, &seqs, t, rate_matrix::Tn93::default(), fit),
+        Model::Tim => run(&args, &seqs, t, rate_matrix::Tim::default(), fit),
+        Model::Tvm => run(&args, &seqs, t, rate_matrix::Tvm::default(), fit),
+        Model::Sym => run(&args, &seqs, t, rate_matrix::Sym::default(), fit),
+        Model::Gtr => run(&args, &seqs, t, rate_matrix::Gtr::default(), fit),
+    }
+}
 
-    let p = rate_matrix::Gtr::default();
-    let mut gen_data = create_genetic_data(&args.alignment, &t, &p.get_matrix());
+// Likelihood of the starting tree and the tree search, with a model fitted by
+// model selection or the model from the command line
+fn run<R: RateMatrix>(args: &Args, seqs: &[Vec<u8>], t: Topology, mut p: R, fit: Option<ModelFit>) {
+    let rates = match fit {
+        Some(fit) => {
+            p = fit.rate_matrix(p);
+            fit.rates
+        }
+        None => {
+            if args.empirical_freqs {
+                if !p.has_free_freqs() {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            "--empirical-freqs needs a model with free base frequencies",
+                        )
+                        .exit();
+                }
+                p.set_freqs(empirical_base_freqs(seqs));
+            }
+            SiteRates::new(args.gamma_cats, args.alpha, args.pinv)
+        }
+    };
+    let mut gen_data = create_genetic_data_from_seqs(
+        seqs,
+        &t,
+        &p.get_matrix(),
+        &rates,
+        args.ascertainment(),
+        args.kernel,
+        args.threads,
+    );
 
-    let ll = t.likelihood(&gen_data);
+    let ll = t.likelihood(&gen_data, &p, &rates);
     // let mge_mat = na::Matrix2::new(0.4, 0.6, 0.6, 0.4);
     // let mut st = create_dummy_statedata(1, &t, &mge_mat);
 
     let mut ts = TreeState {
         top: t,
         mat: p,
+        rates,
         likelihood: ll,
     };
 
@@ -61,6 +181,13 @@ pub fn main() {
             println!("Iteration {}", i);
             let mv = PeturbVec { n: 10 };
             ts = apply_move(ts, mv, hillclimb_accept, &mut gen_data);
+            let mv = MatrixMove {
+                scale: 0.5,
+                window: 0.05,
+            };
+            ts = apply_move(ts, mv, hillclimb_accept, &mut gen_data);
+            let mv = RandomBranchMove { n: 10, sigma: 0.2 };
+            ts = apply_move(ts, mv, hillclimb_accept, &mut gen_data);
         }
         let end = Instant::now();
         println!("New likelihood: {:?}", ts.likelihood);
@@ -68,6 +195,70 @@ pub fn main() {
         eprintln!("Done in {}ms", end.duration_since(start).as_millis());
     }
 
+    // Recalculate every node so the partials match the final tree
+    let mut gen_data = create_internal_data(gen_data, &ts.top, &ts.mat.get_matrix(), &ts.rates);
+
+    if !args.no_optimise {
+        ts = optimise_branch_lengths(ts, &mut gen_data, 1e-4);
+        println!(
+            "Likelihood with optimised branch lengths: {:?}",
+            ts.likelihood
+        );
+        println!("{:?}", ts.top.get_newick());
+    }
+
+    if let Some(optimiser) = args.optimise_params {
+        let free = FreeParams {
+            rates: true,
+            freqs: !args.empirical_freqs,
+            alpha: true,
+            branch_lengths: true,
+        };
+        (ts, gen_data) = optimise_parameters(ts, gen_data, free, optimiser, args.max_iters)
+            .expect("Parameter optimisation failed");
+        println!("Likelihood with optimised parameters: {:?}", ts.likelihood);
+        println!("{:?}", ts.top.get_newick());
+    }
+
+    if let Some(filename) = &args.site_lh {
+        let site_ll = ts.top.site_likelihoods(&gen_data, &ts.mat, &ts.rates);
+        write_site_likelihoods(filename, &[site_ll]).expect("Could not write site likelihoods");
+    }
+
+    if let Some(prefix) = &args.ancestral {
+        let states = marginal_ancestral(&ts.top, &gen_data, &ts.mat, &ts.rates);
+        write_ancestral_fasta(&format!("{}.fasta", prefix), &states, &gen_data)
+            .expect("Could not write ancestral sequences");
+        write_ancestral_states(&format!("{}.state", prefix), &states, &gen_data)
+            .expect("Could not write ancestral states");
+    }
+
+    if args.mutations.is_some() || args.snp_tree.is_some() {
+        let states = joint_ancestral(&ts.top, &gen_data, &ts.mat, &ts.rates);
+        let mutations = states.branch_mutations(&ts.top, &gen_data);
+        if let Some(filename) = &args.mutations {
+            write_mutations(filename, &mutations, &ts.top).expect("Could not write mutations");
+        }
+        if let Some(filename) = &args.snp_tree {
+            let snp_counts = mutations.iter().map(|(n, m)| (*n, m.len())).collect();
+            std::fs::write(filename, ts.top.get_newick_snps(&snp_counts))
+                .expect("Could not write tree");
+        }
+    }
+
+    if let Some(filename) = &args.homoplasy {
+        let report = homoplasy_report(&ts, &gen_data);
+        let n_homoplastic = report.iter().filter(|s| s.is_homoplastic()).count();
+        match ensemble_consistency_index(&report) {
+            Some(ci) => eprintln!(
+                "Homoplastic sites: {}, consistency index: {:.4}",
+                n_homoplastic, ci
+            ),
+            None => eprintln!("No substitutions mapped onto the tree"),
+        }
+        write_homoplasy(filename, &report).expect("Could not write homoplasy report");
+    }
+
     // let mut rng = rand::thread_rng();
     // let distr = rand::distributions::Bernoulli::new(0.5).unwrap();
 
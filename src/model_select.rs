@@ -0,0 +1,361 @@
+use crate::cli::{Criterion, Model};
+use crate::genetic_data::{
+    create_genetic_data_from_seqs, create_internal_data, empirical_base_freqs, Ascertainment,
+    GeneticData, Kernel,
+};
+use crate::rate_matrix::*;
+use crate::site_rates::SiteRates;
+use crate::topology::Topology;
+use clap::ValueEnum;
+use std::collections::HashMap;
+
+// Log-likelihood and information criteria of one substitution model fitted on a fixed tree
+#[derive(Debug, Clone)]
+pub struct ModelFit {
+    pub model: Model,
+    pub params: Vec<f64>,
+    pub rates: SiteRates,
+    pub tree_scale: f64,
+    pub name: String,
+    pub log_likelihood: f64,
+    pub n_params: usize,
+    pub aic: f64,
+    pub aicc: f64,
+    pub bic: f64,
+}
+
+impl ModelFit {
+    pub fn score(&self, criterion: Criterion) -> f64 {
+        match criterion {
+            Criterion::Aic => self.aic,
+            Criterion::Aicc => self.aicc,
+            Criterion::Bic => self.bic,
+        }
+    }
+
+    // Set the fitted parameters on a rate matrix of the same model
+    pub fn rate_matrix<R: RateMatrix>(&self, mut p: R) -> R {
+        p.update_params(self.params.clone());
+        p
+    }
+}
+
+// AIC, AICc and BIC from the log-likelihood, number of free parameters k and
+// number of alignment sites n
+pub fn information_criteria(log_likelihood: f64, k: usize, n: usize) -> (f64, f64, f64) {
+    let (k, n) = (k as f64, n as f64);
+    let aic = 2.0 * k - 2.0 * log_likelihood;
+    let aicc = if n - k - 1.0 > 0.0 {
+        aic + 2.0 * k * (k + 1.0) / (n - k - 1.0)
+    } else {
+        f64::INFINITY
+    };
+    let bic = k * n.ln() - 2.0 * log_likelihood;
+    (aic, aicc, bic)
+}
+
+// Brent's method for the maximum of f on [a, b], returning (x, f(x))
+pub fn brent_maximise<F: FnMut(f64) -> f64>(mut f: F, a: f64, b: f64, tol: f64) -> (f64, f64) {
+    const GOLDEN: f64 = 0.381_966_011_250_105;
+    let (mut a, mut b) = (a, b);
+    let mut x = a + GOLDEN * (b - a);
+    let (mut w, mut v) = (x, x);
+    let mut fx = -f(x);
+    let (mut fw, mut fv) = (fx, fx);
+    let (mut d, mut e): (f64, f64) = (0.0, 0.0);
+
+    for _ in 0..100 {
+        let xm = 0.5 * (a + b);
+        let tol1 = tol * x.abs() + 1e-10;
+        let tol2 = 2.0 * tol1;
+        if (x - xm).abs() <= tol2 - 0.5 * (b - a) {
+            break;
+        }
+        // Try a parabolic step, otherwise fall back to golden section
+        let mut golden_step = true;
+        if e.abs() > tol1 {
+            let r = (x - w) * (fx - fv);
+            let mut q = (x - v) * (fx - fw);
+            let mut p = (x - v) * q - (x - w) * r;
+            q = 2.0 * (q - r);
+            if q > 0.0 {
+                p = -p;
+            }
+            q = q.abs();
+            let e_prev = e;
+            e = d;
+            if p.abs() < (0.5 * q * e_prev).abs() && p > q * (a - x) && p < q * (b - x) {
+                d = p / q;
+                let u = x + d;
+                if u - a < tol2 || b - u < tol2 {
+                    d = tol1.copysign(xm - x);
+                }
+                golden_step = false;
+            }
+        }
+        if golden_step {
+            e = if x >= xm { a - x } else { b - x };
+            d = GOLDEN * e;
+        }
+
+        let u = if d.abs() >= tol1 {
+            x + d
+        } else {
+            x + tol1.copysign(d)
+        };
+        let fu = -f(u);
+        if fu <= fx {
+            if u >= x {
+                a = x;
+            } else {
+                b = x;
+            }
+            (v, w, x) = (w, x, u);
+            (fv, fw, fx) = (fw, fx, fu);
+        } else {
+            if u < x {
+                a = u;
+            } else {
+                b = u;
+            }
+            if fu <= fw || w == x {
+                (v, w) = (w, u);
+                (fv, fw) = (fw, fu);
+            } else if fu <= fv || v == x || v == w {
+                v = u;
+                fv = fu;
+            }
+        }
+    }
+
+    (x, -fx)
+}
+
+// Likelihood on a fixed tree with all branch lengths multiplied by tree_scale,
+// recalculating all the internal partials
+fn fixed_tree_likelihood<R: RateMatrix>(
+    data: &mut Option<GeneticData>,
+    top: &Topology,
+    p: &R,
+    tree_scale: f64,
+    rates: &SiteRates,
+) -> f64 {
+    // Scaling Q is the same as scaling every branch length
+    let matrix = p.get_matrix() * tree_scale;
+    let gen_data = create_internal_data(data.take().unwrap(), top, &matrix, rates);
+    let ll = top.likelihood(&gen_data, p, rates);
+    *data = Some(gen_data);
+    ll
+}
+
+// Maximum likelihood model parameters on a fixed topology, by optimising one parameter
+// at a time. Branch lengths keep their proportions and are fitted by a single tree
+// scale. Exchangeabilities, alpha and the scale are searched on a log scale, base
+// frequencies are fixed at their empirical values (+F). The site rates decide whether
+// alpha (more than one category) and the proportion of invariant sites (pinv > 0) are
+// fitted. Returns the model, site rates, tree scale and log-likelihood
+pub fn fit_model<R: RateMatrix>(
+    mut p: R,
+    mut rates: SiteRates,
+    top: &Topology,
+    gen_data: GeneticData,
+    freqs: [f64; 4],
+) -> (R, SiteRates, f64, f64) {
+    if p.has_free_freqs() {
+        p.set_freqs(freqs);
+    }
+    let n_exch = p.n_free_params() - if p.has_free_freqs() { 3 } else { 0 };
+    let fit_alpha = rates.get_n_cats() > 1;
+    let fit_pinv = rates.get_pinv() > 0.0;
+
+    let mut data = Some(gen_data);
+    let mut tree_scale = 1.0;
+    let mut ll = fixed_tree_likelihood(&mut data, top, &p, tree_scale, &rates);
+
+    for _ in 0..50 {
+        let start_ll = ll;
+
+        let (x, new_ll) = brent_maximise(
+            |x| fixed_tree_likelihood(&mut data, top, &p, x.exp(), &rates),
+            (1e-4_f64).ln(),
+            (10.0_f64).ln(),
+            1e-4,
+        );
+        if new_ll > ll {
+            tree_scale = x.exp();
+            ll = new_ll;
+        }
+
+        for i in 0..n_exch {
+            let (x, new_ll) = brent_maximise(
+                |x| {
+                    let mut params = p.get_params();
+                    params[i] = x.exp();
+                    let mut trial = p;
+                    trial.update_params(params);
+                    fixed_tree_likelihood(&mut data, top, &trial, tree_scale, &rates)
+                },
+                (1e-3_f64).ln(),
+                (1e3_f64).ln(),
+                1e-4,
+            );
+            if new_ll > ll {
+                let mut params = p.get_params();
+                params[i] = x.exp();
+                p.update_params(params);
+                ll = new_ll;
+            }
+        }
+
+        if fit_alpha {
+            let pinv = rates.get_pinv();
+            let (x, new_ll) = brent_maximise(
+                |x| {
+                    let trial = SiteRates::new(rates.get_n_cats(), x.exp(), pinv);
+                    fixed_tree_likelihood(&mut data, top, &p, tree_scale, &trial)
+                },
+                (0.02_f64).ln(),
+                (100.0_f64).ln(),
+                1e-4,
+            );
+            if new_ll > ll {
+                rates.update_params(vec![x.exp(), pinv]);
+                ll = new_ll;
+            }
+        }
+
+        if fit_pinv {
+            let alpha = rates.get_alpha();
+            let (x, new_ll) = brent_maximise(
+                |x| {
+                    let trial = SiteRates::new(rates.get_n_cats(), alpha, x);
+                    fixed_tree_likelihood(&mut data, top, &p, tree_scale, &trial)
+                },
+                0.0,
+                0.95,
+                1e-4,
+            );
+            if new_ll > ll {
+                rates.update_params(vec![alpha, x]);
+                ll = new_ll;
+            }
+        }
+
+        if ll - start_ll < 1e-3 {
+            break;
+        }
+    }
+
+    (p, rates, tree_scale, ll)
+}
+
+fn fit_candidate<R: RateMatrix>(
+    p: R,
+    model: Model,
+    rates: SiteRates,
+    top: &Topology,
+    gen_data: GeneticData,
+    freqs: [f64; 4],
+) -> ModelFit {
+    let n_sites = gen_data.n_sites();
+    let mut name = model.to_possible_value().unwrap().get_name().to_uppercase();
+    let mut n_params = p.n_free_params();
+    if rates.get_pinv() > 0.0 {
+        name.push_str("+I");
+        n_params += 1;
+    }
+    if rates.get_n_cats() > 1 {
+        name.push_str(&format!("+G{}", rates.get_n_cats()));
+        n_params += 1;
+    }
+    // Branch lengths are also estimated in the tree search, so count as parameters
+    n_params += 2 * top.count_leaves() - 3;
+
+    let (p, rates, tree_scale, log_likelihood) = fit_model(p, rates, top, gen_data, freqs);
+    let (aic, aicc, bic) = information_criteria(log_likelihood, n_params, n_sites);
+    ModelFit {
+        model,
+        params: p.get_params(),
+        rates,
+        tree_scale,
+        name,
+        log_likelihood,
+        n_params,
+        aic,
+        aicc,
+        bic,
+    }
+}
+
+// Fits every substitution model on the tree, optionally also with invariant sites and
+// gamma rates with n_cats categories, returning fits from best to worst
+pub fn select_model(
+    seqs: &[Vec<u8>],
+    top: &Topology,
+    ascertainment: Ascertainment,
+    kernel: Kernel,
+    threads: usize,
+    rate_het_cats: Option<usize>,
+    criterion: Criterion,
+) -> Vec<ModelFit> {
+    let freqs = empirical_base_freqs(seqs);
+    let mut rate_models = vec![SiteRates::default()];
+    if let Some(n_cats) = rate_het_cats {
+        rate_models.push(SiteRates::new(1, 1.0, 0.1));
+        rate_models.push(SiteRates::new(n_cats, 1.0, 0.0));
+        rate_models.push(SiteRates::new(n_cats, 1.0, 0.1));
+    }
+
+    let mut fits = Vec::new();
+    // The leaf partials only depend on the number of rate categories, and each fit
+    // recalculates the internal nodes, so they are built once for each
+    let mut leaf_data: HashMap<usize, GeneticData> = HashMap::new();
+    for rates in rate_models {
+        let data = leaf_data.entry(rates.get_n_cats()).or_insert_with(|| {
+            create_genetic_data_from_seqs(
+                seqs,
+                top,
+                &Jc69::default().get_matrix(),
+                &rates,
+                ascertainment,
+                kernel,
+                threads,
+            )
+        });
+        for model in Model::value_variants() {
+            let gen_data = data.clone();
+            let rates = rates.clone();
+            let fit = match model {
+                Model::Jc69 => fit_candidate(Jc69::default(), *model, rates, top, gen_data, freqs),
+                Model::K80 => fit_candidate(K80::default(), *model, rates, top, gen_data, freqs),
+                Model::F81 => fit_candidate(F81::default(), *model, rates, top, gen_data, freqs),
+                Model::Hky85 => {
+                    fit_candidate(Hky85::default(), *model, rates, top, gen_data, freqs)
+                }
+                Model::Tn93 => fit_candidate(Tn93::default(), *model, rates, top, gen_data, freqs),
+                Model::Tim => fit_candidate(Tim::default(), *model, rates, top, gen_data, freqs),
+                Model::Tvm => fit_candidate(Tvm::default(), *model, rates, top, gen_data, freqs),
+                Model::Sym => fit_candidate(Sym::default(), *model, rates, top, gen_data, freqs),
+                Model::Gtr => fit_candidate(Gtr::default(), *model, rates, top, gen_data, freqs),
+            };
+            fits.push(fit);
+        }
+    }
+
+    fits.sort_by(|a, b| a.score(criterion).total_cmp(&b.score(criterion)));
+    fits
+}
+
+// Written to stderr, as stdout holds the likelihood and tree
+pub fn print_model_table(fits: &[ModelFit]) {
+    eprintln!(
+        "{:<16} {:>14} {:>4} {:>14} {:>14} {:>14}",
+        "Model", "LogL", "df", "AIC", "AICc", "BIC"
+    );
+    for fit in fits {
+        eprintln!(
+            "{:<16} {:>14.4} {:>4} {:>14.4} {:>14.4} {:>14.4}",
+            fit.name, fit.log_likelihood, fit.n_params, fit.aic, fit.aicc, fit.bic
+        );
+    }
+}
@@ -20,12 +20,23 @@ pub trait TreeMove<R: RateMatrix> {
     ) -> (Option<Topology>, Option<R>, Option<Vec<usize>>);
 }
 
+// Topology of a tree vector with the names of old, and the branch above each node the
+// same length as in old. Only nodes with new children then need new partials
+fn from_vec_like(tree_vec: &[usize], old: &Topology) -> Topology {
+    let mut new_topology = from_vec(tree_vec);
+    new_topology.names = old.names.clone();
+    for (node, old_node) in new_topology.nodes.iter_mut().zip(old.nodes.iter()) {
+        node.set_branchlen(old_node.get_branchlen());
+    }
+    new_topology
+}
+
 impl<R: RateMatrix> TreeMove<R> for ExactMove {
     fn generate(
         &self,
         current_treestate: &TreeState<R>,
     ) -> (Option<Topology>, Option<R>, Option<Vec<usize>>) {
-        let new_topology = from_vec(&self.target_vector);
+        let new_topology = from_vec_like(&self.target_vector, &current_treestate.top);
         let changes: Option<Vec<usize>> = current_treestate.top.find_changes(&new_topology);
         (Some(new_topology), None, changes)
     }
@@ -71,7 +82,7 @@ impl<R: RateMatrix> TreeMove<R> for PeturbVec {
             };
         }
 
-        let new_topology: Topology = from_vec(&vout);
+        let new_topology: Topology = from_vec_like(&vout, &ts.top);
         let changes: Option<Vec<usize>> = ts.top.find_changes(&new_topology);
         (Some(new_topology), None, changes)
     }
@@ -85,6 +96,7 @@ impl<R: RateMatrix> TreeMove<R> for ChildSwap {
         let mut new_topology: Topology = Topology {
             nodes: ts.top.nodes.clone(),
             tree_vec: ts.top.tree_vec.clone(),
+            names: ts.top.names.clone(),
         };
 
         // Select indices of internal nodes
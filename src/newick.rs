@@ -0,0 +1,349 @@
+use std::fmt;
+
+// A problem in a Newick string, at a byte offset from its start
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewickError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for NewickError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for NewickError {}
+
+fn error<T>(offset: usize, message: &str) -> Result<T, NewickError> {
+    Err(NewickError {
+        offset,
+        message: message.to_string(),
+    })
+}
+
+// Label as written in Newick, in single quotes if it has spaces or characters with
+// another meaning
+pub fn quote_label(label: &str) -> String {
+    if label.is_empty() || label.contains(|c: char| c.is_whitespace() || "()[],:;'\"".contains(c)) {
+        format!("'{}'", label.replace('\'', "''"))
+    } else {
+        label.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Comma,
+    Colon,
+    Semicolon,
+    // Quoted or unquoted label, which after a colon is a branch length
+    Label(String),
+    // Contents of a square bracket comment
+    Comment(String),
+}
+
+// Splits a Newick string into tokens, each with its byte offset. Whitespace outside
+// quotes is dropped, and a doubled quote inside a quoted label is a literal quote
+fn tokenise(newick: &str) -> Result<Vec<(usize, Token)>, NewickError> {
+    let mut tokens = Vec::new();
+    let mut chars = newick.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            ';' => Token::Semicolon,
+            ']' => return error(start, "Unmatched ']'"),
+            '[' => {
+                let mut comment = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, ']')) => break,
+                        Some((_, c)) => comment.push(c),
+                        None => return error(start, "Unterminated comment"),
+                    }
+                }
+                Token::Comment(comment)
+            }
+            '\'' | '"' => {
+                let mut label = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, q)) if q == c => {
+                            if chars.next_if(|(_, next)| *next == c).is_some() {
+                                label.push(c);
+                            } else {
+                                break;
+                            }
+                        }
+                        Some((_, x)) => label.push(x),
+                        None => return error(start, "Unterminated quoted label"),
+                    }
+                }
+                Token::Label(label)
+            }
+            c if c.is_whitespace() => continue,
+            c => {
+                let mut label = String::from(c);
+                while let Some((_, x)) =
+                    chars.next_if(|(_, x)| !x.is_whitespace() && !"()[],:;'\"".contains(*x))
+                {
+                    label.push(x);
+                }
+                Token::Label(label)
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NewickNode {
+    pub label: Option<String>,
+    pub length: Option<f64>,
+    // Contents of [&...] comments on the node, without the brackets or ampersand
+    pub comments: Vec<String>,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    // Byte offset where the node starts
+    pub offset: usize,
+}
+
+impl NewickNode {
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    // Label of an internal node read as a number, as written for bootstrap support
+    pub fn support(&self) -> Option<f64> {
+        match self.is_leaf() {
+            true => None,
+            false => self.label.as_ref()?.parse().ok(),
+        }
+    }
+}
+
+// A tree read from a Newick string, with any number of children at each node
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewickTree {
+    pub nodes: Vec<NewickNode>,
+    pub root: usize,
+}
+
+// Adds a node below parent, returning its ID
+fn add_node(nodes: &mut Vec<NewickNode>, parent: Option<usize>, offset: usize) -> usize {
+    let id = nodes.len();
+    nodes.push(NewickNode {
+        parent,
+        offset,
+        ..Default::default()
+    });
+    if let Some(p) = parent {
+        nodes[p].children.push(id);
+    }
+    id
+}
+
+// Reads a Newick string, keeping multifurcations. Labels may be quoted, branch lengths
+// may be missing, and comments other than [&...] are ignored. The final semicolon is
+// optional. The root is node 0
+pub fn parse_newick(newick: &str) -> Result<NewickTree, NewickError> {
+    let mut nodes: Vec<NewickNode> = Vec::new();
+    // Internal nodes whose closing bracket has not been read, with the offset of the
+    // opening bracket
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    // Node whose label, length and comments are being read, or None where a new node
+    // starts
+    let mut current: Option<usize> = None;
+    let mut finished = false;
+
+    let mut tokens = tokenise(newick)?.into_iter().peekable();
+    while let Some((offset, token)) = tokens.next() {
+        if finished && !matches!(token, Token::Comment(_)) {
+            return error(offset, "Unexpected text after ';'");
+        }
+        // Only one tree may be read, so a node after the root is closed is an error
+        let complete = open.is_empty() && !nodes.is_empty();
+        let parent = open.last().map(|(id, _)| *id);
+
+        match token {
+            Token::Open => {
+                if current.is_some() {
+                    return error(offset, "Unexpected '('");
+                }
+                if complete {
+                    return error(offset, "Expected ';' after the tree");
+                }
+                open.push((add_node(&mut nodes, parent, offset), offset));
+            }
+            Token::Comma | Token::Close => {
+                if open.is_empty() {
+                    let c = if token == Token::Comma { "','" } else { "')'" };
+                    return error(offset, &format!("Unexpected {} outside brackets", c));
+                }
+                if current.is_none() {
+                    // A leaf with no label or length
+                    add_node(&mut nodes, parent, offset);
+                }
+                current = match token {
+                    Token::Close => open.pop().map(|(id, _)| id),
+                    _ => None,
+                };
+            }
+            Token::Label(label) => {
+                let id = match current {
+                    None if complete => return error(offset, "Expected ';' after the tree"),
+                    None => add_node(&mut nodes, parent, offset),
+                    Some(id) if nodes[id].label.is_some() || nodes[id].length.is_some() => {
+                        return error(offset, &format!("Unexpected label '{}'", label))
+                    }
+                    Some(id) => id,
+                };
+                nodes[id].label = Some(label);
+                current = Some(id);
+            }
+            Token::Colon => {
+                let id = match current {
+                    None if complete => return error(offset, "Expected ';' after the tree"),
+                    None => add_node(&mut nodes, parent, offset),
+                    Some(id) if nodes[id].length.is_some() => {
+                        return error(offset, "Second branch length for a node")
+                    }
+                    Some(id) => id,
+                };
+                // Comments may come between the colon and the length
+                while let Some((_, Token::Comment(comment))) = tokens.peek() {
+                    if let Some(c) = comment.strip_prefix('&') {
+                        nodes[id].comments.push(c.to_string());
+                    }
+                    tokens.next();
+                }
+                let length = match tokens.next() {
+                    Some((offset, Token::Label(length))) => match length.parse::<f64>() {
+                        Ok(bl) if bl.is_finite() => bl,
+                        _ => return error(offset, &format!("Invalid branch length '{}'", length)),
+                    },
+                    Some((offset, _)) => return error(offset, "Expected a branch length"),
+                    None => return error(newick.len(), "Expected a branch length"),
+                };
+                nodes[id].length = Some(length);
+                current = Some(id);
+            }
+            Token::Semicolon => {
+                if let Some((_, open_offset)) = open.last() {
+                    return error(*open_offset, "Unclosed '('");
+                }
+                if nodes.is_empty() {
+                    return error(offset, "Empty tree");
+                }
+                finished = true;
+            }
+            Token::Comment(comment) => {
+                if let (Some(id), Some(c)) = (current, comment.strip_prefix('&')) {
+                    nodes[id].comments.push(c.to_string());
+                }
+            }
+        }
+    }
+
+    if let Some((_, open_offset)) = open.last() {
+        return error(*open_offset, "Unclosed '('");
+    }
+    if nodes.is_empty() {
+        return error(newick.len(), "Empty tree");
+    }
+    Ok(NewickTree { nodes, root: 0 })
+}
+
+impl NewickTree {
+    pub fn leaves(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|i| self.nodes[*i].is_leaf())
+            .collect()
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.nodes
+            .iter()
+            .all(|n| n.is_leaf() || n.children.len() == 2)
+    }
+
+    // Nodes with every child before its parent, and children in the order written
+    pub fn postorder(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![self.root];
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            stack.extend(self.nodes[node].children.iter());
+        }
+        order.reverse();
+        order
+    }
+
+    // Makes every internal node have two children. Nodes with one child are removed,
+    // adding their branch length to the child's, and the children of a multifurcation
+    // are joined in order, ((a,b),c) for (a,b,c), by new nodes on branches of length
+    // zero. Nodes are then numbered postorder
+    pub fn resolve_multifurcations(&mut self) {
+        for node in self.postorder() {
+            if self.nodes[node].children.len() == 1 {
+                let child = self.nodes[node].children[0];
+                let parent = self.nodes[node].parent;
+                if let Some(bl) = self.nodes[node].length {
+                    *self.nodes[child].length.get_or_insert(0.0) += bl;
+                }
+                self.nodes[child].parent = parent;
+                match parent {
+                    Some(p) => {
+                        for c in self.nodes[p].children.iter_mut() {
+                            if *c == node {
+                                *c = child;
+                            }
+                        }
+                    }
+                    None => self.root = child,
+                }
+                self.nodes[node].children.clear();
+                self.nodes[node].parent = None;
+            }
+            while self.nodes[node].children.len() > 2 {
+                let joined: Vec<usize> = self.nodes[node].children.drain(..2).collect();
+                let id = self.nodes.len();
+                for c in joined.iter() {
+                    self.nodes[*c].parent = Some(id);
+                }
+                let offset = self.nodes[joined[0]].offset;
+                self.nodes.push(NewickNode {
+                    length: Some(0.0),
+                    parent: Some(node),
+                    children: joined,
+                    offset,
+                    ..Default::default()
+                });
+                self.nodes[node].children.insert(0, id);
+            }
+        }
+        // Drop the removed nodes, renumbering the rest
+        let kept: Vec<usize> = self.postorder();
+        let mut new_id = vec![None; self.nodes.len()];
+        for (i, node) in kept.iter().enumerate() {
+            new_id[*node] = Some(i);
+        }
+        let nodes = kept
+            .iter()
+            .map(|node| {
+                let mut n = self.nodes[*node].clone();
+                n.parent = n.parent.and_then(|p| new_id[p]);
+                n.children = n.children.iter().map(|c| new_id[*c].unwrap()).collect();
+                n
+            })
+            .collect();
+        self.nodes = nodes;
+        self.root = new_id[self.root].unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+use crate::branchlength::{branch_gradient, MAX_BRANCH_LEN, MIN_BRANCH_LEN};
+use crate::genetic_data::{create_internal_data, eigen_decomposition, GeneticData};
+use crate::rate_matrix::RateMatrix;
+use crate::site_rates::SiteRates;
+use crate::topology::Topology;
+use crate::treestate::TreeState;
+use argmin::core::{CostFunction, Error, Executor, Gradient, State};
+use argmin::solver::linesearch::MoreThuenteLineSearch;
+use argmin::solver::neldermead::NelderMead;
+use argmin::solver::quasinewton::LBFGS;
+use std::cell::RefCell;
+
+// Which parameters of the likelihood are optimised
+#[derive(Debug, Clone, Copy)]
+pub struct FreeParams {
+    pub rates: bool,
+    pub freqs: bool,
+    pub alpha: bool,
+    pub branch_lengths: bool,
+}
+
+// Numerical optimisers from argmin
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Optimiser {
+    /// Limited memory BFGS with a More-Thuente line search, using analytic gradients
+    /// for branch lengths and numerical gradients for the model parameters
+    Lbfgs,
+    /// Nelder-Mead simplex, without gradients
+    NelderMead,
+}
+
+// Negative log-likelihood of a fixed topology as a function of unconstrained
+// parameters, in the order: free exchangeabilities as logs, base frequencies as log
+// ratios to the frequency of T, log gamma alpha, then the log length of the branch
+// above each node other than the root. Models with a redundant exchangeability keep
+// the last one fixed
+pub struct LikelihoodCost<R: RateMatrix> {
+    pub top: Topology,
+    pub mat: R,
+    pub rates: SiteRates,
+    pub free: FreeParams,
+    // Internal partials are recalculated for each evaluation
+    data: RefCell<Option<GeneticData>>,
+}
+
+impl<R: RateMatrix> LikelihoodCost<R> {
+    pub fn new(ts: TreeState<R>, gen_data: GeneticData, free: FreeParams) -> Self {
+        LikelihoodCost {
+            top: ts.top,
+            mat: ts.mat,
+            rates: ts.rates,
+            free,
+            data: RefCell::new(Some(gen_data)),
+        }
+    }
+
+    fn n_rates(&self) -> usize {
+        match self.free.rates {
+            true => self.mat.n_free_params() - if self.mat.has_free_freqs() { 3 } else { 0 },
+            false => 0,
+        }
+    }
+
+    fn fit_freqs(&self) -> bool {
+        self.free.freqs && self.mat.has_free_freqs()
+    }
+
+    fn fit_alpha(&self) -> bool {
+        self.free.alpha && self.rates.get_n_cats() > 1
+    }
+
+    fn branches(&self) -> Vec<usize> {
+        match self.free.branch_lengths {
+            true => self
+                .top
+                .nodes
+                .iter()
+                .filter(|node| node.get_parent().is_some())
+                .map(|node| node.get_id())
+                .collect(),
+            false => Vec::new(),
+        }
+    }
+
+    // The current values as unconstrained parameters
+    pub fn params(&self) -> Vec<f64> {
+        let mut x: Vec<f64> = self.mat.get_params()[..self.n_rates()]
+            .iter()
+            .map(|r| r.ln())
+            .collect();
+        if self.fit_freqs() {
+            let freqs = self.mat.get_freqs();
+            x.extend(freqs[..3].iter().map(|f| (f / freqs[3]).ln()));
+        }
+        if self.fit_alpha() {
+            x.push(self.rates.get_alpha().ln());
+        }
+        for node in self.branches() {
+            x.push(self.top.nodes[node].get_branchlen().ln());
+        }
+        x
+    }
+
+    // Topology, rate matrix and site rates for unconstrained parameters
+    pub fn apply(&self, x: &[f64]) -> (Topology, R, SiteRates) {
+        let (mut top, mut mat, mut rates) = (self.top.clone(), self.mat, self.rates.clone());
+        let mut x = x.iter();
+
+        let n_rates = self.n_rates();
+        if n_rates > 0 {
+            let mut params = mat.get_params();
+            for (p, xi) in params[..n_rates].iter_mut().zip(x.by_ref()) {
+                *p = xi.clamp(-10.0, 10.0).exp();
+            }
+            mat.update_params(params);
+        }
+        if self.fit_freqs() {
+            let ratios: Vec<f64> = x
+                .by_ref()
+                .take(3)
+                .map(|xi| xi.clamp(-20.0, 20.0).exp())
+                .collect();
+            let total = 1.0 + ratios.iter().sum::<f64>();
+            mat.set_freqs([
+                ratios[0] / total,
+                ratios[1] / total,
+                ratios[2] / total,
+                1.0 / total,
+            ]);
+        }
+        if self.fit_alpha() {
+            let alpha = x.next().unwrap().clamp(0.01_f64.ln(), 200.0_f64.ln()).exp();
+            rates.update_params(vec![alpha, rates.get_pinv()]);
+        }
+        for (node, xi) in self.branches().into_iter().zip(x) {
+            let bl = xi.clamp(MIN_BRANCH_LEN.ln(), MAX_BRANCH_LEN.ln()).exp();
+            top.nodes[node].set_branchlen(bl);
+        }
+        (top, mat, rates)
+    }
+
+    pub fn log_likelihood(&self, x: &[f64]) -> f64 {
+        let (top, mat, rates) = self.apply(x);
+        let mut data = self.data.borrow_mut();
+        let gen_data = create_internal_data(data.take().unwrap(), &top, &mat.get_matrix(), &rates);
+        let ll = top.likelihood(&gen_data, &mat, &rates);
+        *data = Some(gen_data);
+        ll
+    }
+
+    // The tree state at x, and the partials calculated for it
+    pub fn into_tree_state(self, x: &[f64]) -> (TreeState<R>, GeneticData) {
+        let likelihood = self.log_likelihood(x);
+        let (top, mat, rates) = self.apply(x);
+        let ts = TreeState {
+            top,
+            mat,
+            rates,
+            likelihood,
+        };
+        (ts, self.data.into_inner().unwrap())
+    }
+}
+
+impl<R: RateMatrix> CostFunction for LikelihoodCost<R> {
+    type Param = Vec<f64>;
+    type Output = f64;
+
+    fn cost(&self, x: &Self::Param) -> Result<Self::Output, Error> {
+        Ok(-self.log_likelihood(x))
+    }
+}
+
+// Central differences for the model parameters. Branch lengths use the analytic
+// derivatives from one pass over the tree, unless Q is not reversible
+impl<R: RateMatrix> Gradient for LikelihoodCost<R> {
+    type Param = Vec<f64>;
+    type Gradient = Vec<f64>;
+
+    fn gradient(&self, x: &Self::Param) -> Result<Self::Gradient, Error> {
+        const H: f64 = 1e-5;
+        let central = |i: usize| {
+            let mut shifted = x.clone();
+            shifted[i] = x[i] + H;
+            let up = self.log_likelihood(&shifted);
+            shifted[i] = x[i] - H;
+            let down = self.log_likelihood(&shifted);
+            -(up - down) / (2.0 * H)
+        };
+
+        let branches = self.branches();
+        let n_model = x.len() - branches.len();
+        let mut gradient: Vec<f64> = (0..n_model).map(central).collect();
+
+        let (top, mat, rates) = self.apply(x);
+        match eigen_decomposition(&mat.get_matrix()) {
+            Some(eigen) if !branches.is_empty() => {
+                let mut data = self.data.borrow_mut();
+                let gen_data =
+                    create_internal_data(data.take().unwrap(), &top, &mat.get_matrix(), &rates);
+                let d1 = branch_gradient(&top, &gen_data, &mat, &rates, &eigen);
+                *data = Some(gen_data);
+                let bounds = MIN_BRANCH_LEN.ln()..MAX_BRANCH_LEN.ln();
+                for (node, xi) in branches.iter().zip(x[n_model..].iter()) {
+                    // On the log scale, and flat where the length is clamped
+                    gradient.push(match bounds.contains(xi) {
+                        true => -d1[*node] * top.nodes[*node].get_branchlen(),
+                        false => 0.0,
+                    });
+                }
+            }
+            _ => gradient.extend((n_model..x.len()).map(central)),
+        }
+        Ok(gradient)
+    }
+}
+
+// Maximum likelihood values of the free parameters on the topology of ts, starting
+// from their current values. Returns the new tree state and its partials
+pub fn optimise_parameters<R: RateMatrix>(
+    ts: TreeState<R>,
+    gen_data: GeneticData,
+    free: FreeParams,
+    optimiser: Optimiser,
+    max_iters: u64,
+) -> Result<(TreeState<R>, GeneticData), Error> {
+    let cost = LikelihoodCost::new(ts, gen_data, free);
+    let x0 = cost.params();
+    if x0.is_empty() {
+        return Ok(cost.into_tree_state(&x0));
+    }
+
+    let (mut problem, best) = match optimiser {
+        Optimiser::Lbfgs => {
+            let solver = LBFGS::new(MoreThuenteLineSearch::new(), 7).with_tolerance_cost(1e-6)?;
+            let result = Executor::new(cost, solver)
+                .configure(|state| state.param(x0.clone()).max_iters(max_iters))
+                .run()?;
+            (result.problem, result.state.get_best_param().cloned())
+        }
+        Optimiser::NelderMead => {
+            // Starting simplex with a step of 0.1 on the log scale in each direction
+            let mut simplex = vec![x0.clone()];
+            for i in 0..x0.len() {
+                let mut vertex = x0.clone();
+                vertex[i] += 0.1;
+                simplex.push(vertex);
+            }
+            let solver = NelderMead::new(simplex).with_sd_tolerance(1e-6)?;
+            let result = Executor::new(cost, solver)
+                .configure(|state| state.max_iters(max_iters))
+                .run()?;
+            (result.problem, result.state.get_best_param().cloned())
+        }
+    };
+
+    let cost = problem.take_problem().unwrap();
+    Ok(cost.into_tree_state(&best.unwrap_or(x0)))
+}
@@ -0,0 +1,119 @@
+use crate::ancestral::{AncestralStates, Mutation};
+use crate::genetic_data::GeneticData;
+use crate::homoplasy::SiteHomoplasy;
+use crate::topology::Topology;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Writes the site log-likelihoods of one or more trees in the TREE-PUZZLE/RAxML
+// .sitelh format, as read by CONSEL: the number of trees and sites on the first
+// line, then one line per tree
+pub fn write_site_likelihoods(filename: &str, site_ll: &[Vec<f64>]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    let n_sites = site_ll.first().map_or(0, |ll| ll.len());
+    writeln!(out, "  {}  {}", site_ll.len(), n_sites)?;
+    for (i, tree_ll) in site_ll.iter().enumerate() {
+        let values: Vec<String> = tree_ll.iter().map(|ll| format!("{:.6}", ll)).collect();
+        writeln!(out, "tr{}\t{}", i + 1, values.join(" "))?;
+    }
+    out.flush()
+}
+
+// Writes the most probable sequence at each internal node as FASTA, named by node ID
+pub fn write_ancestral_fasta(
+    filename: &str,
+    states: &AncestralStates,
+    gen_data: &GeneticData,
+) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    for node in states.nodes() {
+        let seq = gen_data.expand_patterns(&states.sequence(node));
+        writeln!(out, ">Node{}", node)?;
+        out.write_all(&seq)?;
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+// Writes the posterior probabilities of each state at every internal node and site,
+// in the same tab-separated layout as an IQ-TREE .state file
+pub fn write_ancestral_states(
+    filename: &str,
+    states: &AncestralStates,
+    gen_data: &GeneticData,
+) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    writeln!(out, "Node\tSite\tState\tp_A\tp_C\tp_G\tp_T")?;
+    for node in states.nodes() {
+        let seq = states.sequence(node);
+        let posteriors = &states.posteriors[&node];
+        for (site, pattern) in gen_data.site_patterns.iter().enumerate() {
+            let probs = posteriors.row(*pattern);
+            writeln!(
+                out,
+                "Node{}\t{}\t{}\t{:.5}\t{:.5}\t{:.5}\t{:.5}",
+                node,
+                site + 1,
+                seq[*pattern] as char,
+                probs[0],
+                probs[1],
+                probs[2],
+                probs[3]
+            )?;
+        }
+    }
+    out.flush()
+}
+
+// Writes the substitutions on each branch as a tab-separated table, one row per
+// substitution, with the branch given by the node below it and sites counted from one
+pub fn write_mutations(
+    filename: &str,
+    mutations: &HashMap<usize, Vec<Mutation>>,
+    top: &Topology,
+) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    writeln!(out, "Node\tParent\tSite\tFrom\tTo")?;
+    let mut nodes: Vec<usize> = mutations.keys().copied().collect();
+    nodes.sort();
+    for node in nodes {
+        let parent = top.nodes[node].get_parent().unwrap();
+        for m in &mutations[&node] {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                node,
+                parent,
+                m.site + 1,
+                m.from as char,
+                m.to as char
+            )?;
+        }
+    }
+    out.flush()
+}
+
+// Writes the homoplastic sites, counted from one, with their number of substitutions,
+// consistency index and each branch as node:from>to
+pub fn write_homoplasy(filename: &str, report: &[SiteHomoplasy]) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    writeln!(out, "Site\tChanges\tMinChanges\tCI\tBranches")?;
+    for site in report.iter().filter(|s| s.is_homoplastic()) {
+        let branches: Vec<String> = site
+            .branches
+            .iter()
+            .map(|(node, m)| format!("{}:{}>{}", node, m.from as char, m.to as char))
+            .collect();
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{:.4}\t{}",
+            site.site + 1,
+            site.n_changes(),
+            site.min_changes,
+            site.consistency_index(),
+            branches.join(",")
+        )?;
+    }
+    out.flush()
+}
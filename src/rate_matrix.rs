@@ -1,8 +1,10 @@
+use crate::moves::TreeMove;
 use crate::topology::Topology;
+use crate::treestate::TreeState;
+use crate::BF_DEFAULT;
 use rand::distributions::{Distribution, Uniform};
+use rand::Rng;
 use statrs::distribution::Dirichlet;
-// use crate::TreeState;
-// use crate::treestate::TreeMove;
 
 pub trait RateMatrix: Copy {
     fn update_matrix(&mut self);
@@ -15,29 +17,202 @@ pub trait RateMatrix: Copy {
 
     fn get_params(&self) -> Vec<f64>;
 
+    // Stationary base frequencies (A, C, G, T), used at the root of the tree
+    fn get_freqs(&self) -> [f64; 4];
+
+    // Use new base frequencies, e.g. counted from the alignment
+    fn set_freqs(&mut self, freqs: [f64; 4]);
+
+    // Whether the base frequencies are parameters of the model (+F)
+    fn has_free_freqs(&self) -> bool;
+
+    // Number of free parameters, with the rates relative to one another and
+    // frequencies summing to one
+    fn n_free_params(&self) -> usize;
+
+    // Whether Q is rescaled to one expected substitution per unit branch length
+    fn get_normalised(&self) -> bool;
+
+    fn set_normalised(&mut self, normalised: bool);
+
     fn matrix_move(&self) -> Self;
 }
 
-// pub struct MatrixMove {}
+// Expected number of substitutions per unit time under the stationary distribution
+pub fn mean_rate(matrix: &na::Matrix4<f64>, freqs: [f64; 4]) -> f64 {
+    -freqs
+        .iter()
+        .enumerate()
+        .fold(0.0, |acc, (i, f)| acc + f * matrix[(i, i)])
+}
+
+// Rescales Q so that branch lengths are in substitutions per site
+pub fn normalise(matrix: na::Matrix4<f64>, freqs: [f64; 4]) -> na::Matrix4<f64> {
+    matrix / mean_rate(&matrix, freqs)
+}
+
+// Builds a time-reversible Q from the exchangeabilities (AC, AG, AT, CG, CT, GT)
+// and the base frequencies
+pub fn reversible_matrix(rates: [f64; 6], freqs: [f64; 4]) -> na::Matrix4<f64> {
+    let [ac, ag, at, cg, ct, gt] = rates;
+    let [p0, p1, p2, p3] = freqs;
+    na::Matrix4::new(
+        -(ac * p1 + ag * p2 + at * p3),
+        ac * p1,
+        ag * p2,
+        at * p3,
+        ac * p0,
+        -(ac * p0 + cg * p2 + ct * p3),
+        cg * p2,
+        ct * p3,
+        ag * p0,
+        cg * p1,
+        -(ag * p0 + cg * p1 + gt * p3),
+        gt * p3,
+        at * p0,
+        ct * p1,
+        gt * p2,
+        -(at * p0 + ct * p1 + gt * p2),
+    )
+}
+
+// Diagonalised reversible rate matrix, Q = A diag(eigenvalues) A^-1, so that
+// P(t) = A diag(exp(eigenvalues * t)) A^-1 only needs a matrix product per branch
+#[derive(Debug, Clone, Copy)]
+pub struct EigenDecomposition {
+    pub eigenvalues: na::Vector4<f64>,
+    pub eigenvectors: na::Matrix4<f64>,
+    pub inverse: na::Matrix4<f64>,
+}
+
+impl EigenDecomposition {
+    // None if Q is not reversible. The stationary frequencies are recovered from
+    // detailed balance, pi_i q_ij = pi_j q_ji, and Q is made symmetric by
+    // diag(pi)^1/2 Q diag(pi)^-1/2 so its eigenvectors are orthogonal
+    pub fn new(matrix: &na::Matrix4<f64>) -> Option<Self> {
+        let mut freqs = [1.0; 4];
+        for j in 1..4 {
+            if matrix[(0, j)] <= 0.0 || matrix[(j, 0)] <= 0.0 {
+                return None;
+            }
+            freqs[j] = matrix[(0, j)] / matrix[(j, 0)];
+        }
+        let freqs = normalise_freqs(freqs);
+        let scale = matrix.amax();
+        for i in 0..4 {
+            for j in (i + 1)..4 {
+                let flux = freqs[i] * matrix[(i, j)] - freqs[j] * matrix[(j, i)];
+                if flux.abs() > 1e-12 * scale {
+                    return None;
+                }
+            }
+        }
+
+        let sqrt_freqs = na::Vector4::from(freqs.map(f64::sqrt));
+        let symmetric = na::Matrix4::from_fn(|i, j| {
+            let s = sqrt_freqs[i] * matrix[(i, j)] / sqrt_freqs[j];
+            if i == j {
+                s
+            } else {
+                // Average out rounding so the matrix is exactly symmetric
+                0.5 * (s + sqrt_freqs[j] * matrix[(j, i)] / sqrt_freqs[i])
+            }
+        });
+        let eigen = na::SymmetricEigen::new(symmetric);
+        let u = eigen.eigenvectors;
+        Some(EigenDecomposition {
+            eigenvalues: eigen.eigenvalues,
+            eigenvectors: na::Matrix4::from_fn(|i, j| u[(i, j)] / sqrt_freqs[i]),
+            inverse: na::Matrix4::from_fn(|i, j| u[(j, i)] * sqrt_freqs[j]),
+        })
+    }
+
+    pub fn transition_matrix(&self, branch_len: f64) -> na::Matrix4<f64> {
+        let exp_diag =
+            na::Matrix4::from_diagonal(&self.eigenvalues.map(|l| (l * branch_len).exp()));
+        // Rounding can leave tiny negative probabilities
+        (self.eigenvectors * exp_diag * self.inverse).map(|p| p.max(0.0))
+    }
+
+    // P(t) and its first and second derivatives with respect to t, which only
+    // multiply each eigenvalue term by lambda and lambda^2
+    pub fn transition_derivatives(&self, branch_len: f64) -> [na::Matrix4<f64>; 3] {
+        let exp = self.eigenvalues.map(|l| (l * branch_len).exp());
+        [0, 1, 2].map(|k| {
+            let diag = exp.zip_map(&self.eigenvalues, |e, l| e * l.powi(k));
+            self.eigenvectors * na::Matrix4::from_diagonal(&diag) * self.inverse
+        })
+    }
+}
+
+fn normalise_freqs(freqs: [f64; 4]) -> [f64; 4] {
+    let total: f64 = freqs.iter().sum();
+    freqs.map(|f| f / total)
+}
+
+// Draw from a flat Dirichlet distribution, as used for fresh parameter proposals
+fn dirichlet_draw(k: usize) -> Vec<f64> {
+    let d = Dirichlet::new_with_param(1.0, k).unwrap();
+    d.sample(&mut rand::thread_rng()).iter().copied().collect()
+}
+
+// Local change to the parameters of a rate matrix: either one exchangeability is
+// multiplied by exp(scale * (u - 1/2)), or a random amount up to window is moved
+// between two base frequencies, reflecting at zero so the proposal is symmetric.
+// Models without free parameters are returned unchanged
+pub fn local_matrix_move<R: RateMatrix>(p: &R, scale: f64, window: f64) -> R {
+    let mut rng = rand::thread_rng();
+    let mut params = p.get_params();
+    // Frequencies are the last four parameters, with three free
+    let (n_freqs, free_freqs) = if p.has_free_freqs() { (4, 3) } else { (0, 0) };
+    let n_rates = params.len() - n_freqs;
+    let free_rates = p.n_free_params() > free_freqs;
 
-// impl<R: RateMatrix> TreeMove<R> for MatrixMove {
-//     fn generate(&self, ts: &TreeState<R>) -> TreeState<R> {
-//         let rm = ts.mat.matrix_move();
-//         let changes: Vec<usize> = ts.top.postorder_notips(ts.top.get_root()).map(|n| n.get_id()).collect();
-//         // This is not ideal
-//         let new_top = Topology{
-//             nodes: ts.top.nodes.clone(),
-//             tree_vec: ts.top.tree_vec.clone(),
-//         };
+    let move_freqs = match (free_rates, n_freqs > 0) {
+        (false, false) => return *p,
+        (true, true) => rng.gen_bool(0.5),
+        (_, has_freqs) => has_freqs,
+    };
+    if move_freqs {
+        // Frequency parameters may not be normalised, so the window is scaled by their sum
+        let freqs = &mut params[n_rates..];
+        let step = window * freqs.iter().sum::<f64>();
+        let picked = rand::seq::index::sample(&mut rng, 4, 2);
+        let (i, j) = (picked.index(0), picked.index(1));
+        let total = freqs[i] + freqs[j];
+        // Reflect back into (0, total)
+        let mut fi = (freqs[i] + rng.gen_range(-step..step)).abs();
+        if fi > total {
+            fi = 2.0 * total - fi;
+        }
+        freqs[i] = fi.clamp(1e-6 * total, (1.0 - 1e-6) * total);
+        freqs[j] = total - freqs[i];
+    } else {
+        let i = rng.gen_range(0..n_rates);
+        params[i] *= (scale * (rng.gen::<f64>() - 0.5)).exp();
+    }
 
-//         TreeState{
-//             top: new_top,
-//             mat: rm,
-//             ll: ts.ll,
-//             changed_nodes: Some(changes),
-//         }
-//     }
-// }
+    let mut new = *p;
+    new.update_params(params);
+    new
+}
+
+// Proposes new rate matrix parameters with local_matrix_move. The topology is kept,
+// and apply_move recalculates every internal node for the new matrix
+pub struct MatrixMove {
+    pub scale: f64,
+    pub window: f64,
+}
+
+impl<R: RateMatrix> TreeMove<R> for MatrixMove {
+    fn generate(&self, ts: &TreeState<R>) -> (Option<Topology>, Option<R>, Option<Vec<usize>>) {
+        if ts.mat.n_free_params() == 0 {
+            return (None, None, None);
+        }
+        let new_mat = local_matrix_move(&ts.mat, self.scale, self.window);
+        (None, Some(new_mat), None)
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct Gtr {
@@ -52,6 +227,7 @@ pub struct Gtr {
     p1: f64,
     p2: f64,
     p3: f64,
+    normalised: bool,
 }
 
 impl RateMatrix for Gtr {
@@ -83,6 +259,30 @@ impl RateMatrix for Gtr {
         self.matrix = mat;
     }
 
+    fn get_freqs(&self) -> [f64; 4] {
+        let total = self.p0 + self.p1 + self.p2 + self.p3;
+        [
+            self.p0 / total,
+            self.p1 / total,
+            self.p2 / total,
+            self.p3 / total,
+        ]
+    }
+
+    fn set_freqs(&mut self, freqs: [f64; 4]) {
+        let mut params = self.get_params();
+        params[6..10].copy_from_slice(&freqs);
+        self.update_params(params);
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        true
+    }
+
+    fn n_free_params(&self) -> usize {
+        8
+    }
+
     fn update_matrix(&mut self) {
         self.matrix = na::Matrix4::new(
             -(self.a * self.p1 + self.b * self.p2 + self.c * self.p3),
@@ -102,6 +302,18 @@ impl RateMatrix for Gtr {
             self.f * self.p2,
             -(self.c * self.p0 + self.e * self.p1 + self.f * self.p2),
         );
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
     }
 
     fn matrix_move(&self) -> Self {
@@ -113,7 +325,10 @@ impl RateMatrix for Gtr {
 
         // let params: Vec<f64> = pars.iter().chain(pars2.iter()).map(|x| *x).collect();
         let params: Vec<f64> = pars.iter().chain(pars2.iter()).copied().collect();
-        let mut new: Self = Self::default();
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
         new.update_params(params);
         new.update_matrix();
         new
@@ -134,6 +349,7 @@ impl Default for Gtr {
             p1: 0.25,
             p2: 0.25,
             p3: 0.25,
+            normalised: true,
         };
         out.update_matrix();
         out
@@ -144,6 +360,7 @@ impl Default for Gtr {
 pub struct Jc69 {
     matrix: na::Matrix4<f64>,
     mu: f64,
+    normalised: bool,
 }
 
 impl RateMatrix for Jc69 {
@@ -159,6 +376,22 @@ impl RateMatrix for Jc69 {
         vec![self.mu]
     }
 
+    fn get_freqs(&self) -> [f64; 4] {
+        BF_DEFAULT
+    }
+
+    fn set_freqs(&mut self, _freqs: [f64; 4]) {
+        panic!("JC69 has equal base frequencies, use F81 to change them");
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        false
+    }
+
+    fn n_free_params(&self) -> usize {
+        0
+    }
+
     fn update_params(&mut self, params: Vec<f64>) {
         self.mu = params[0];
     }
@@ -182,12 +415,27 @@ impl RateMatrix for Jc69 {
             self.mu / 4.0,
             -(3.0 * self.mu) / 4.0,
         );
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
     }
 
     fn matrix_move(&self) -> Self {
         let dist = Uniform::new(0.0, 1.0);
         let params = vec![dist.sample(&mut rand::thread_rng())];
-        let mut new: Self = Self::default();
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
         new.update_params(params);
         new.update_matrix();
         new
@@ -199,6 +447,632 @@ impl Default for Jc69 {
         let mut out = Jc69 {
             matrix: na::Matrix4::identity(),
             mu: 4.0 / 3.0,
+            normalised: true,
+        };
+        out.update_matrix();
+        out
+    }
+}
+
+// Kimura (1980) model, with transition/transversion ratio kappa and equal base frequencies
+#[derive(Debug, Clone, Copy)]
+pub struct K80 {
+    matrix: na::Matrix4<f64>,
+    kappa: f64,
+    normalised: bool,
+}
+
+impl RateMatrix for K80 {
+    fn get_params(&self) -> Vec<f64> {
+        vec![self.kappa]
+    }
+
+    fn update_params(&mut self, params: Vec<f64>) {
+        self.kappa = params[0];
+        self.update_matrix();
+    }
+
+    fn get_matrix(&self) -> na::Matrix4<f64> {
+        self.matrix
+    }
+
+    fn set_matrix(&mut self, mat: na::Matrix4<f64>) {
+        self.matrix = mat;
+    }
+
+    fn get_freqs(&self) -> [f64; 4] {
+        BF_DEFAULT
+    }
+
+    fn set_freqs(&mut self, _freqs: [f64; 4]) {
+        panic!("K80 has equal base frequencies, use HKY85 to change them");
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        false
+    }
+
+    fn n_free_params(&self) -> usize {
+        1
+    }
+
+    fn update_matrix(&mut self) {
+        let k = self.kappa;
+        self.matrix = reversible_matrix([1.0, k, 1.0, 1.0, k, 1.0], self.get_freqs());
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
+    }
+
+    fn matrix_move(&self) -> Self {
+        // Relative rates of transversions and transitions
+        let rates = dirichlet_draw(2);
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
+        new.update_params(vec![rates[1] / rates[0]]);
+        new
+    }
+}
+
+impl Default for K80 {
+    fn default() -> Self {
+        let mut out = K80 {
+            matrix: na::Matrix4::identity(),
+            kappa: 1.0,
+            normalised: true,
+        };
+        out.update_matrix();
+        out
+    }
+}
+
+// Felsenstein (1981) model, equal exchangeabilities with unequal base frequencies
+#[derive(Debug, Clone, Copy)]
+pub struct F81 {
+    matrix: na::Matrix4<f64>,
+    freqs: [f64; 4],
+    normalised: bool,
+}
+
+impl RateMatrix for F81 {
+    fn get_params(&self) -> Vec<f64> {
+        self.freqs.to_vec()
+    }
+
+    fn update_params(&mut self, params: Vec<f64>) {
+        self.freqs = [params[0], params[1], params[2], params[3]];
+        self.update_matrix();
+    }
+
+    fn get_matrix(&self) -> na::Matrix4<f64> {
+        self.matrix
+    }
+
+    fn set_matrix(&mut self, mat: na::Matrix4<f64>) {
+        self.matrix = mat;
+    }
+
+    fn get_freqs(&self) -> [f64; 4] {
+        normalise_freqs(self.freqs)
+    }
+
+    fn set_freqs(&mut self, freqs: [f64; 4]) {
+        self.update_params(freqs.to_vec());
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        true
+    }
+
+    fn n_free_params(&self) -> usize {
+        3
+    }
+
+    fn update_matrix(&mut self) {
+        self.matrix = reversible_matrix([1.0; 6], self.get_freqs());
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
+    }
+
+    fn matrix_move(&self) -> Self {
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
+        new.update_params(dirichlet_draw(4));
+        new
+    }
+}
+
+impl Default for F81 {
+    fn default() -> Self {
+        let mut out = F81 {
+            matrix: na::Matrix4::identity(),
+            freqs: BF_DEFAULT,
+            normalised: true,
+        };
+        out.update_matrix();
+        out
+    }
+}
+
+// Hasegawa, Kishino and Yano (1985) model, K80 with unequal base frequencies
+#[derive(Debug, Clone, Copy)]
+pub struct Hky85 {
+    matrix: na::Matrix4<f64>,
+    kappa: f64,
+    freqs: [f64; 4],
+    normalised: bool,
+}
+
+impl RateMatrix for Hky85 {
+    fn get_params(&self) -> Vec<f64> {
+        let mut params = vec![self.kappa];
+        params.extend_from_slice(&self.freqs);
+        params
+    }
+
+    fn update_params(&mut self, params: Vec<f64>) {
+        self.kappa = params[0];
+        self.freqs = [params[1], params[2], params[3], params[4]];
+        self.update_matrix();
+    }
+
+    fn get_matrix(&self) -> na::Matrix4<f64> {
+        self.matrix
+    }
+
+    fn set_matrix(&mut self, mat: na::Matrix4<f64>) {
+        self.matrix = mat;
+    }
+
+    fn get_freqs(&self) -> [f64; 4] {
+        normalise_freqs(self.freqs)
+    }
+
+    fn set_freqs(&mut self, freqs: [f64; 4]) {
+        self.freqs = freqs;
+        self.update_matrix();
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        true
+    }
+
+    fn n_free_params(&self) -> usize {
+        4
+    }
+
+    fn update_matrix(&mut self) {
+        let k = self.kappa;
+        self.matrix = reversible_matrix([1.0, k, 1.0, 1.0, k, 1.0], self.get_freqs());
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
+    }
+
+    fn matrix_move(&self) -> Self {
+        let rates = dirichlet_draw(2);
+        let mut params = vec![rates[1] / rates[0]];
+        params.extend(dirichlet_draw(4));
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
+        new.update_params(params);
+        new
+    }
+}
+
+impl Default for Hky85 {
+    fn default() -> Self {
+        let mut out = Hky85 {
+            matrix: na::Matrix4::identity(),
+            kappa: 1.0,
+            freqs: BF_DEFAULT,
+            normalised: true,
+        };
+        out.update_matrix();
+        out
+    }
+}
+
+// Tamura and Nei (1993) model, with separate purine (A-G) and pyrimidine (C-T)
+// transition rates relative to transversions
+#[derive(Debug, Clone, Copy)]
+pub struct Tn93 {
+    matrix: na::Matrix4<f64>,
+    kappa_r: f64,
+    kappa_y: f64,
+    freqs: [f64; 4],
+    normalised: bool,
+}
+
+impl RateMatrix for Tn93 {
+    fn get_params(&self) -> Vec<f64> {
+        let mut params = vec![self.kappa_r, self.kappa_y];
+        params.extend_from_slice(&self.freqs);
+        params
+    }
+
+    fn update_params(&mut self, params: Vec<f64>) {
+        self.kappa_r = params[0];
+        self.kappa_y = params[1];
+        self.freqs = [params[2], params[3], params[4], params[5]];
+        self.update_matrix();
+    }
+
+    fn get_matrix(&self) -> na::Matrix4<f64> {
+        self.matrix
+    }
+
+    fn set_matrix(&mut self, mat: na::Matrix4<f64>) {
+        self.matrix = mat;
+    }
+
+    fn get_freqs(&self) -> [f64; 4] {
+        normalise_freqs(self.freqs)
+    }
+
+    fn set_freqs(&mut self, freqs: [f64; 4]) {
+        self.freqs = freqs;
+        self.update_matrix();
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        true
+    }
+
+    fn n_free_params(&self) -> usize {
+        5
+    }
+
+    fn update_matrix(&mut self) {
+        let (kr, ky) = (self.kappa_r, self.kappa_y);
+        self.matrix = reversible_matrix([1.0, kr, 1.0, 1.0, ky, 1.0], self.get_freqs());
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
+    }
+
+    fn matrix_move(&self) -> Self {
+        // Relative rates of transversions, purine and pyrimidine transitions
+        let rates = dirichlet_draw(3);
+        let mut params = vec![rates[1] / rates[0], rates[2] / rates[0]];
+        params.extend(dirichlet_draw(4));
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
+        new.update_params(params);
+        new
+    }
+}
+
+impl Default for Tn93 {
+    fn default() -> Self {
+        let mut out = Tn93 {
+            matrix: na::Matrix4::identity(),
+            kappa_r: 1.0,
+            kappa_y: 1.0,
+            freqs: BF_DEFAULT,
+            normalised: true,
+        };
+        out.update_matrix();
+        out
+    }
+}
+
+// Transition model, with A-C = G-T and A-T = C-G and separate transition rates.
+// Rates are relative to A-C
+#[derive(Debug, Clone, Copy)]
+pub struct Tim {
+    matrix: na::Matrix4<f64>,
+    ag: f64,
+    at: f64,
+    ct: f64,
+    freqs: [f64; 4],
+    normalised: bool,
+}
+
+impl RateMatrix for Tim {
+    fn get_params(&self) -> Vec<f64> {
+        let mut params = vec![self.ag, self.at, self.ct];
+        params.extend_from_slice(&self.freqs);
+        params
+    }
+
+    fn update_params(&mut self, params: Vec<f64>) {
+        self.ag = params[0];
+        self.at = params[1];
+        self.ct = params[2];
+        self.freqs = [params[3], params[4], params[5], params[6]];
+        self.update_matrix();
+    }
+
+    fn get_matrix(&self) -> na::Matrix4<f64> {
+        self.matrix
+    }
+
+    fn set_matrix(&mut self, mat: na::Matrix4<f64>) {
+        self.matrix = mat;
+    }
+
+    fn get_freqs(&self) -> [f64; 4] {
+        normalise_freqs(self.freqs)
+    }
+
+    fn set_freqs(&mut self, freqs: [f64; 4]) {
+        self.freqs = freqs;
+        self.update_matrix();
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        true
+    }
+
+    fn n_free_params(&self) -> usize {
+        6
+    }
+
+    fn update_matrix(&mut self) {
+        let rates = [1.0, self.ag, self.at, self.at, self.ct, 1.0];
+        self.matrix = reversible_matrix(rates, self.get_freqs());
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
+    }
+
+    fn matrix_move(&self) -> Self {
+        let rates = dirichlet_draw(4);
+        let mut params: Vec<f64> = rates[1..].iter().map(|r| r / rates[0]).collect();
+        params.extend(dirichlet_draw(4));
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
+        new.update_params(params);
+        new
+    }
+}
+
+impl Default for Tim {
+    fn default() -> Self {
+        let mut out = Tim {
+            matrix: na::Matrix4::identity(),
+            ag: 1.0,
+            at: 1.0,
+            ct: 1.0,
+            freqs: BF_DEFAULT,
+            normalised: true,
+        };
+        out.update_matrix();
+        out
+    }
+}
+
+// Transversion model, with A-G = C-T and separate transversion rates.
+// Rates are relative to G-T
+#[derive(Debug, Clone, Copy)]
+pub struct Tvm {
+    matrix: na::Matrix4<f64>,
+    ac: f64,
+    ag: f64,
+    at: f64,
+    cg: f64,
+    freqs: [f64; 4],
+    normalised: bool,
+}
+
+impl RateMatrix for Tvm {
+    fn get_params(&self) -> Vec<f64> {
+        let mut params = vec![self.ac, self.ag, self.at, self.cg];
+        params.extend_from_slice(&self.freqs);
+        params
+    }
+
+    fn update_params(&mut self, params: Vec<f64>) {
+        self.ac = params[0];
+        self.ag = params[1];
+        self.at = params[2];
+        self.cg = params[3];
+        self.freqs = [params[4], params[5], params[6], params[7]];
+        self.update_matrix();
+    }
+
+    fn get_matrix(&self) -> na::Matrix4<f64> {
+        self.matrix
+    }
+
+    fn set_matrix(&mut self, mat: na::Matrix4<f64>) {
+        self.matrix = mat;
+    }
+
+    fn get_freqs(&self) -> [f64; 4] {
+        normalise_freqs(self.freqs)
+    }
+
+    fn set_freqs(&mut self, freqs: [f64; 4]) {
+        self.freqs = freqs;
+        self.update_matrix();
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        true
+    }
+
+    fn n_free_params(&self) -> usize {
+        7
+    }
+
+    fn update_matrix(&mut self) {
+        let rates = [self.ac, self.ag, self.at, self.cg, self.ag, 1.0];
+        self.matrix = reversible_matrix(rates, self.get_freqs());
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
+    }
+
+    fn matrix_move(&self) -> Self {
+        let rates = dirichlet_draw(5);
+        let mut params: Vec<f64> = rates[..4].iter().map(|r| r / rates[4]).collect();
+        params.extend(dirichlet_draw(4));
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
+        new.update_params(params);
+        new
+    }
+}
+
+impl Default for Tvm {
+    fn default() -> Self {
+        let mut out = Tvm {
+            matrix: na::Matrix4::identity(),
+            ac: 1.0,
+            ag: 1.0,
+            at: 1.0,
+            cg: 1.0,
+            freqs: BF_DEFAULT,
+            normalised: true,
+        };
+        out.update_matrix();
+        out
+    }
+}
+
+// Symmetric model, GTR exchangeabilities (AC, AG, AT, CG, CT, GT) with equal base frequencies
+#[derive(Debug, Clone, Copy)]
+pub struct Sym {
+    matrix: na::Matrix4<f64>,
+    rates: [f64; 6],
+    normalised: bool,
+}
+
+impl RateMatrix for Sym {
+    fn get_params(&self) -> Vec<f64> {
+        self.rates.to_vec()
+    }
+
+    fn update_params(&mut self, params: Vec<f64>) {
+        self.rates.copy_from_slice(&params[0..6]);
+        self.update_matrix();
+    }
+
+    fn get_matrix(&self) -> na::Matrix4<f64> {
+        self.matrix
+    }
+
+    fn set_matrix(&mut self, mat: na::Matrix4<f64>) {
+        self.matrix = mat;
+    }
+
+    fn get_freqs(&self) -> [f64; 4] {
+        BF_DEFAULT
+    }
+
+    fn set_freqs(&mut self, _freqs: [f64; 4]) {
+        panic!("SYM has equal base frequencies, use GTR to change them");
+    }
+
+    fn has_free_freqs(&self) -> bool {
+        false
+    }
+
+    fn n_free_params(&self) -> usize {
+        5
+    }
+
+    fn update_matrix(&mut self) {
+        self.matrix = reversible_matrix(self.rates, self.get_freqs());
+        if self.normalised {
+            self.matrix = normalise(self.matrix, self.get_freqs());
+        }
+    }
+
+    fn get_normalised(&self) -> bool {
+        self.normalised
+    }
+
+    fn set_normalised(&mut self, normalised: bool) {
+        self.normalised = normalised;
+        self.update_matrix();
+    }
+
+    fn matrix_move(&self) -> Self {
+        let mut new: Self = Self {
+            normalised: self.normalised,
+            ..Self::default()
+        };
+        new.update_params(dirichlet_draw(6));
+        new
+    }
+}
+
+impl Default for Sym {
+    fn default() -> Self {
+        let mut out = Sym {
+            matrix: na::Matrix4::identity(),
+            rates: [1.0; 6],
+            normalised: true,
         };
         out.update_matrix();
         out
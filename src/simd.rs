@@ -0,0 +1,127 @@
+use crate::genetic_data::{scaling_exponent, SCALING_THRESHOLD};
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2};
+
+// Vectorised version of the linear-space kernel for four states, with one site per
+// 256-bit register. Chosen at runtime when the CPU has AVX and FMA, otherwise
+// node_likelihood_scaled_scalar is used. The log-space kernel needs an exp and a log
+// for each pair of states, so stays scalar: Kernel::Linear is the fast path
+
+pub fn available() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        is_x86_feature_detected!("avx") && is_x86_feature_detected!("fma")
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
+}
+
+// Same as node_likelihood_scaled, or None if the CPU does not support it or the
+// partials are not contiguous in memory
+pub fn node_likelihood_scaled_simd(
+    left: (ArrayView2<f64>, ArrayView1<i32>),
+    right: (ArrayView2<f64>, ArrayView1<i32>),
+    matrixl: &[na::Matrix4<f64>],
+    matrixr: &[na::Matrix4<f64>],
+) -> Option<(Array2<f64>, Array1<i32>)> {
+    if !available() {
+        return None;
+    }
+    let (seql, scalel) = (left.0.as_slice()?, left.1.as_slice()?);
+    let (seqr, scaler) = (right.0.as_slice()?, right.1.as_slice()?);
+    let n_rows = scalel.len();
+    let mut partials = Array2::zeros((n_rows, 4));
+    let mut scalers = Array1::zeros(n_rows);
+
+    #[cfg(target_arch = "x86_64")]
+    // Safety: AVX and FMA were detected above, and all slices have 4 * n_rows or
+    // n_rows elements
+    unsafe {
+        scaled_rows_avx(
+            (seql, scalel),
+            (seqr, scaler),
+            matrixl,
+            matrixr,
+            partials.as_slice_mut().unwrap(),
+            scalers.as_slice_mut().unwrap(),
+        );
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = (seql, seqr, scaler, matrixl, matrixr);
+
+    Some((partials, scalers))
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx,fma")]
+unsafe fn scaled_rows_avx(
+    left: (&[f64], &[i32]),
+    right: (&[f64], &[i32]),
+    matrixl: &[na::Matrix4<f64>],
+    matrixr: &[na::Matrix4<f64>],
+    partials: &mut [f64],
+    scalers: &mut [i32],
+) {
+    use std::arch::x86_64::*;
+
+    let n_rows = scalers.len();
+    let n_sites = n_rows / matrixl.len();
+
+    let columns: Vec<_> = matrixl
+        .iter()
+        .zip(matrixr.iter())
+        .map(|(pl, pr)| (matrix_columns(pl), matrix_columns(pr)))
+        .collect();
+
+    let rows = partials.chunks_exact_mut(4).zip(scalers.iter_mut());
+    for (i, (out, out_scale)) in rows.enumerate() {
+        let (cols_l, cols_r) = &columns[i / n_sites];
+        let row = 4 * i..4 * i + 4;
+        let mut node = _mm256_mul_pd(
+            transition_product(cols_l, &left.0[row.clone()]),
+            transition_product(cols_r, &right.0[row]),
+        );
+
+        // Largest of the four states
+        let pairs = _mm_max_pd(_mm256_castpd256_pd128(node), _mm256_extractf128_pd(node, 1));
+        let max = _mm_cvtsd_f64(_mm_max_sd(pairs, _mm_unpackhi_pd(pairs, pairs)));
+
+        let mut scale = left.1[i] + right.1[i];
+        if max > 0.0 && max < SCALING_THRESHOLD {
+            let exponent = scaling_exponent(max);
+            node = _mm256_mul_pd(node, _mm256_set1_pd(2f64.powi(-exponent)));
+            scale += exponent;
+        }
+        _mm256_storeu_pd(out.as_mut_ptr(), node);
+        *out_scale = scale;
+    }
+}
+
+// nalgebra stores matrices column by column, so each column is one load
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx,fma")]
+unsafe fn matrix_columns(m: &na::Matrix4<f64>) -> [std::arch::x86_64::__m256d; 4] {
+    use std::arch::x86_64::*;
+    let m = m.as_slice();
+    [
+        _mm256_loadu_pd(m[0..4].as_ptr()),
+        _mm256_loadu_pd(m[4..8].as_ptr()),
+        _mm256_loadu_pd(m[8..12].as_ptr()),
+        _mm256_loadu_pd(m[12..16].as_ptr()),
+    ]
+}
+
+// P * x, as the sum of the columns of P weighted by the child partials x
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx,fma")]
+unsafe fn transition_product(
+    columns: &[std::arch::x86_64::__m256d; 4],
+    x: &[f64],
+) -> std::arch::x86_64::__m256d {
+    use std::arch::x86_64::*;
+    let mut acc = _mm256_mul_pd(columns[0], _mm256_set1_pd(x[0]));
+    acc = _mm256_fmadd_pd(columns[1], _mm256_set1_pd(x[1]), acc);
+    acc = _mm256_fmadd_pd(columns[2], _mm256_set1_pd(x[2]), acc);
+    _mm256_fmadd_pd(columns[3], _mm256_set1_pd(x[3]), acc)
+}
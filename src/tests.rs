@@ -1,15 +1,52 @@
 #[cfg(test)]
 use crate::always_accept;
+use crate::ancestral::above_branch;
+use crate::ancestral::{joint_ancestral, marginal_ancestral, Mutation};
 use crate::apply_move;
+use crate::branchlength::{
+    branch_derivatives, optimise_branch_lengths, BranchMove, RandomBranchMove, MAX_BRANCH_LEN,
+};
+use crate::cli::{Args, Criterion, Model};
 use crate::create_dummy_gendata;
+use crate::create_genetic_data;
+use crate::create_internal_data;
+use crate::distance::{distance_matrix, neighbour_joining, pairwise_distance, DistanceMethod};
 use crate::from_vec;
+use crate::genetic_data::{
+    char_to_likelihood, empirical_base_freqs, is_constant_site, read_alignment, Ascertainment,
+    GeneticData, Kernel,
+};
+use crate::genetic_data::{
+    eigen_decomposition, matrix_exp, node_likelihood_scaled_scalar, to_log_space,
+    transition_matrices, MATRIX_EXP,
+};
+use crate::homoplasy::{ensemble_consistency_index, homoplasy_report};
+use crate::model_select::{brent_maximise, information_criteria, select_model};
+use crate::newick::parse_newick;
 use crate::newick_to_vector;
+use crate::optimise::{optimise_parameters, FreeParams, LikelihoodCost, Optimiser};
+use crate::output::write_site_likelihoods;
 use crate::random_vector;
-use crate::rate_matrix::Gtr;
 use crate::rate_matrix::RateMatrix;
+use crate::rate_matrix::{
+    local_matrix_move, mean_rate, Gtr, Hky85, Jc69, MatrixMove, Sym, Tim, Tn93, Tvm, F81, K80,
+};
+use crate::simd;
+use crate::simd::node_likelihood_scaled_simd;
+use crate::site_rates::SiteRates;
+use crate::topology::{from_newick, topology_from_newick};
 use crate::ExactMove;
+use crate::PeturbVec;
 use crate::Topology;
+use crate::TreeMove;
 use crate::TreeState;
+use argmin::core::Gradient;
+use assert_fs::TempDir;
+use cached::Cached;
+use clap::Parser;
+use ndarray::s;
+use rand::Rng;
+use std::collections::HashMap;
 
 #[test]
 fn check_topology_build_manual() {
@@ -91,12 +128,14 @@ fn update_tree() {
     let p = Gtr::default();
     let t_1 = from_vec(&[0, 0, 1, 0]);
 
-    let mut gen_data = create_dummy_gendata(2, &t_1, &p.get_matrix());
-    let ll = t_1.likelihood(&gen_data);
+    let rates = SiteRates::default();
+    let mut gen_data = create_dummy_gendata(2, &t_1, &p.get_matrix(), &rates);
+    let ll = t_1.likelihood(&gen_data, &p, &rates);
 
     let mut ts = TreeState {
         top: t_1,
         mat: p,
+        rates,
         likelihood: ll,
     };
 
@@ -125,11 +164,13 @@ fn update_tree() {
 fn likelihood_internal_consistency_check() {
     let p = Gtr::default();
     let t = from_vec(&[0, 0, 0, 0]);
-    let mut gen_data = create_dummy_gendata(5, &t, &p.get_matrix());
-    let ll = t.likelihood(&gen_data);
+    let rates = SiteRates::default();
+    let mut gen_data = create_dummy_gendata(5, &t, &p.get_matrix(), &rates);
+    let ll = t.likelihood(&gen_data, &p, &rates);
     let mut ts = TreeState {
         top: t,
         mat: p,
+        rates,
         likelihood: ll,
     };
 
@@ -147,7 +188,7 @@ fn likelihood_internal_consistency_check() {
 
     ts = apply_move(ts, mv, always_accept, &mut gen_data);
 
-    let new_likelihood = ts.top.likelihood(&gen_data);
+    let new_likelihood = ts.top.likelihood(&gen_data, &ts.mat, &ts.rates);
 
     assert_eq!(old_likelihood, new_likelihood);
 }
@@ -238,3 +279,1582 @@ fn newick_vector_conversion_check() {
     let trstr = from_vec(&y).get_newick();
     assert_eq!(trstr, nw);
 }
+
+#[test]
+fn child_order_invariance() {
+    // Swapping the children of the root must not change the likelihood when the two
+    // branches below it have different lengths
+    let p = Gtr::default();
+    let mut t = from_vec(&[0, 0, 1, 0]);
+    let mut swapped = from_vec(&[0, 0, 1, 0]);
+    for i in 0..t.nodes.len() {
+        t.nodes[i].set_branchlen(0.1 * (i + 1) as f64);
+        swapped.nodes[i].set_branchlen(0.1 * (i + 1) as f64);
+    }
+    let root = t.get_root().get_id();
+    let (l, r) = (t.nodes[root].get_lchild(), t.nodes[root].get_rchild());
+    swapped.nodes[root].set_lchild(r);
+    swapped.nodes[root].set_rchild(l);
+
+    let rates = SiteRates::default();
+    let gen_data = create_dummy_gendata(5, &t, &p.get_matrix(), &rates);
+    let swapped_data = create_internal_data(gen_data.clone(), &swapped, &p.get_matrix(), &rates);
+
+    let ll = t.likelihood(&gen_data, &p, &rates);
+    let swapped_ll = swapped.likelihood(&swapped_data, &p, &rates);
+    assert!((ll - swapped_ll).abs() < 1e-10);
+}
+
+#[test]
+fn gamma_category_rates() {
+    // Mean rates of four categories for alpha = 0.5 (Yang 1994)
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let expected = [0.0334, 0.2519, 0.8203, 2.8944];
+    for (r, e) in rates.get_rates().iter().zip(expected.iter()) {
+        assert!((r - e).abs() < 1e-4);
+    }
+    let mean: f64 = rates.get_rates().iter().sum::<f64>() / 4.0;
+    assert!((mean - 1.0).abs() < 1e-10);
+
+    assert_eq!(SiteRates::default().get_rates(), &[1.0]);
+}
+
+#[test]
+fn gamma_likelihood_consistency_check() {
+    let p = Gtr::default();
+    let t = from_vec(&[0, 0, 0, 0]);
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let mut gen_data = create_dummy_gendata(5, &t, &p.get_matrix(), &rates);
+    let ll = t.likelihood(&gen_data, &p, &rates);
+    let mut ts = TreeState {
+        top: t,
+        mat: p,
+        rates,
+        likelihood: ll,
+    };
+
+    for vec in [vec![0, 0, 0, 1], vec![0, 0, 1, 2], vec![0, 0, 0, 0]] {
+        let mv = ExactMove {
+            target_vector: vec.clone(),
+        };
+        ts = apply_move(ts, mv, always_accept, &mut gen_data);
+        // Full recalculation from the leaves should agree with the partial update
+        let full_data = create_internal_data(gen_data.clone(), &ts.top, &p.get_matrix(), &ts.rates);
+        assert!((ts.likelihood - ts.top.likelihood(&full_data, &ts.mat, &ts.rates)).abs() < 1e-8);
+    }
+    assert!((ts.likelihood - ll).abs() < 1e-8);
+
+    // With a very large alpha all categories have rate ~1
+    let flat = SiteRates::new(4, 1e6, 0.0);
+    let flat_data = create_internal_data(gen_data.clone(), &ts.top, &p.get_matrix(), &flat);
+    let single = SiteRates::default();
+    let n_sites = gen_data.n_patterns();
+    let single_data = create_internal_data(
+        GeneticData::new(
+            gen_data.partials.slice(s![.., 0..n_sites, ..]).to_owned(),
+            4,
+            1,
+        ),
+        &ts.top,
+        &p.get_matrix(),
+        &single,
+    );
+    assert!(
+        (ts.top.likelihood(&flat_data, &p, &flat) - ts.top.likelihood(&single_data, &p, &single))
+            .abs()
+            < 1e-3
+    );
+}
+
+#[test]
+fn constant_site_counts() {
+    let parse = |fconst: &str| Args::try_parse_from(["bactrees", "-a", "x", "--fconst", fconst]);
+    assert_eq!(
+        parse("10, 20,30,40").unwrap().ascertainment(),
+        Ascertainment::Stamatakis([10.0, 20.0, 30.0, 40.0])
+    );
+    for bad in ["10,20,30", "10,20,30,40,50", "10,x,30,40", "10,-20,30,40"] {
+        assert!(parse(bad).is_err());
+    }
+
+    // Felsenstein and Stamatakis need the counts, which Lewis cannot use
+    let parse_asc = |asc: &[&str]| {
+        Args::try_parse_from([&["bactrees", "-a", "x"], asc].concat()).and_then(|args| {
+            args.validate()?;
+            Ok(args)
+        })
+    };
+    assert!(parse_asc(&["--asc", "felsenstein"]).is_err());
+    assert!(parse_asc(&["--asc", "stamatakis"]).is_err());
+    assert!(parse_asc(&["--asc", "lewis", "--fconst", "1,2,3,4"]).is_err());
+    assert_eq!(
+        parse_asc(&["--asc", "lewis"]).unwrap().ascertainment(),
+        Ascertainment::Lewis
+    );
+}
+
+#[test]
+fn pinv_argument() {
+    let parse = |pinv: &str| Args::try_parse_from(["bactrees", "-a", "x", "--pinv", pinv]);
+    assert_eq!(parse("0.25").unwrap().pinv, 0.25);
+    for bad in ["1", "-0.1", "x"] {
+        assert!(parse(bad).is_err());
+    }
+}
+
+#[test]
+fn invariant_sites_likelihood() {
+    let p = Gtr::default();
+    let t = from_vec(&[0, 0, 0, 0]);
+    let no_inv = SiteRates::default();
+    let gen_data = create_dummy_gendata(20, &t, &p.get_matrix(), &no_inv);
+
+    // Count the states each site could be constant in directly from the leaves
+    let n_const: Vec<usize> = (0..20)
+        .map(|i| {
+            (0..4)
+                .filter(|&j| (0..4).all(|leaf| gen_data.partials[[leaf, i, j]] == 0.0))
+                .count()
+        })
+        .collect();
+
+    // +I mixes the variable-site likelihood (with rescaled rate) and the constant-site likelihood
+    let pinv = 0.3;
+    let with_inv = SiteRates::new(1, 1.0, pinv);
+    assert!((with_inv.get_rates()[0] - 1.0 / (1.0 - pinv)).abs() < 1e-12);
+    let inv_data = create_internal_data(gen_data.clone(), &t, &p.get_matrix(), &with_inv);
+    let root = inv_data.partials.slice(s![t.get_root().get_id(), .., ..]);
+    let expected: f64 = (0..20)
+        .map(|i| {
+            let variable: f64 = (0..4).map(|j| root[[i, j]].exp() * 0.25).sum();
+            ((1.0 - pinv) * variable + pinv * 0.25 * n_const[i] as f64).ln()
+        })
+        .sum();
+    assert!((t.likelihood(&inv_data, &p, &with_inv) - expected).abs() < 1e-8);
+
+    // No invariant class gives back the plain likelihood
+    let zero_inv = SiteRates::new(1, 1.0, 0.0);
+    assert_eq!(
+        t.likelihood(&gen_data, &p, &no_inv),
+        t.likelihood(&gen_data, &p, &zero_inv)
+    );
+}
+
+#[test]
+#[should_panic]
+fn invariant_proportion_update() {
+    let mut rates = SiteRates::new(4, 0.5, 0.2);
+    rates.update_params(vec![0.5, 1.0]);
+}
+
+#[test]
+fn site_pattern_compression() {
+    let aln = "tests/test_files_in/listeria0.aln";
+    let p = Gtr::default();
+    let rates = SiteRates::new(2, 0.8, 0.0);
+    let t = from_vec(&random_vector(28));
+    let gen_data = create_genetic_data(
+        aln,
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Log,
+        1,
+    );
+
+    let (_, seqs) = read_alignment(aln);
+    let n_sites = seqs[0].len();
+    assert_eq!(gen_data.n_sites(), n_sites);
+    assert!(gen_data.n_patterns() < n_sites);
+    assert_eq!(gen_data.weights.iter().sum::<f64>(), n_sites as f64);
+
+    // Every column maps back to a pattern with the same leaf states
+    for (loc_i, pat_i) in gen_data.site_patterns.iter().enumerate() {
+        for (seq_i, seq) in seqs.iter().enumerate() {
+            let cur = char_to_likelihood(&(seq[loc_i] as char));
+            for (j, l) in cur.iter().enumerate() {
+                assert_eq!(gen_data.partials[[seq_i, *pat_i, j]], *l);
+            }
+        }
+    }
+
+    // Likelihood is the same as with one pattern per column
+    let mut full = ndarray::Array3::from_elem((55, 2 * n_sites, 4), 0.0);
+    for (seq_i, seq) in seqs.iter().enumerate() {
+        for (loc_i, e) in seq.iter().enumerate() {
+            let cur = char_to_likelihood(&(*e as char));
+            for (j, l) in cur.iter().enumerate() {
+                full[[seq_i, loc_i, j]] = *l;
+                full[[seq_i, n_sites + loc_i, j]] = *l;
+            }
+        }
+    }
+    let full_data =
+        create_internal_data(GeneticData::new(full, 28, 2), &t, &p.get_matrix(), &rates);
+    assert!(
+        (t.likelihood(&gen_data, &p, &rates) - t.likelihood(&full_data, &p, &rates)).abs() < 1e-6
+    );
+}
+
+// Writes sequences to a FASTA file in dir
+fn write_fasta(dir: &TempDir, name: &str, seqs: &[Vec<u8>]) -> String {
+    let path = dir.path().join(name);
+    let mut out = String::new();
+    for (i, seq) in seqs.iter().enumerate() {
+        out.push_str(&format!(">{}\n{}\n", i, String::from_utf8_lossy(seq)));
+    }
+    std::fs::write(&path, out).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+// The first n variable columns of an alignment, as an alignment of SNPs for the
+// ascertainment corrections
+fn variable_sites(seqs: &[Vec<u8>], n: usize) -> Vec<Vec<u8>> {
+    let variable: Vec<usize> = (0..seqs[0].len())
+        .filter(|i| !is_constant_site(seqs, *i))
+        .take(n)
+        .collect();
+    seqs.iter()
+        .map(|seq| variable.iter().map(|i| seq[*i]).collect())
+        .collect()
+}
+
+#[test]
+fn ascertainment_corrections() {
+    // Keep only the first 2000 columns of the alignment that are not constant
+    let (_, seqs) = read_alignment("tests/test_files_in/listeria0.aln");
+    let snps = variable_sites(&seqs, 2000);
+    let dir = TempDir::new().unwrap();
+    let snp_file = write_fasta(&dir, "bactrees_asc_snps.fasta", &snps);
+
+    let p = Gtr::default();
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let t = from_vec(&random_vector(28));
+    let ll_with = |file: &str, asc: Ascertainment| {
+        let gen_data = create_genetic_data(file, &t, &p.get_matrix(), &rates, asc, Kernel::Log, 1);
+        t.likelihood(&gen_data, &p, &rates)
+    };
+
+    // Counting removed constant sites is the same as putting them back in the alignment
+    let counts = [3.0, 1.0, 0.0, 2.0];
+    let mut with_constant = snps.clone();
+    for (base, n) in [b'A', b'C', b'G', b'T'].iter().zip(counts.iter()) {
+        for seq in with_constant.iter_mut() {
+            seq.extend(std::iter::repeat_n(*base, *n as usize));
+        }
+    }
+    let full_file = write_fasta(&dir, "bactrees_asc_full.fasta", &with_constant);
+    let ll_variable = ll_with(&snp_file, Ascertainment::None);
+    let ll_stamatakis = ll_with(&snp_file, Ascertainment::Stamatakis(counts));
+    assert!((ll_stamatakis - ll_with(&full_file, Ascertainment::None)).abs() < 1e-6);
+
+    // Felsenstein gives the probability of a constant site, which Lewis conditions on
+    let ll_felsenstein = ll_with(&snp_file, Ascertainment::Felsenstein(1.0));
+    let p_constant = (ll_felsenstein - ll_variable).exp();
+    assert!(p_constant > 0.0 && p_constant < 1.0);
+    let ll_lewis = ll_with(&snp_file, Ascertainment::Lewis);
+    let expected = ll_variable - snps[0].len() as f64 * (1.0 - p_constant).ln();
+    assert!((ll_lewis - expected).abs() < 1e-6);
+    assert!(ll_lewis > ll_variable);
+}
+
+#[test]
+#[should_panic]
+fn ascertainment_with_constant_sites() {
+    let p = Gtr::default();
+    let rates = SiteRates::default();
+    let t = from_vec(&random_vector(28));
+    create_genetic_data(
+        "tests/test_files_in/listeria0.aln",
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::Lewis,
+        Kernel::Log,
+        1,
+    );
+}
+
+#[test]
+fn stationary_freqs_at_root() {
+    let mut p = Gtr::default();
+    p.update_params(vec![1.0, 2.0, 0.5, 0.8, 3.0, 1.0, 0.1, 0.2, 0.3, 0.4]);
+    let freqs = p.get_freqs();
+    assert_eq!(freqs, [0.1, 0.2, 0.3, 0.4]);
+    // Frequencies are stationary under Q
+    let pi = na::RowVector4::new(freqs[0], freqs[1], freqs[2], freqs[3]);
+    assert!((pi * p.get_matrix()).norm() < 1e-12);
+
+    // The same unrooted tree rooted on different branches has the same likelihood
+    // ((3,1)4,(2,0)5)6 with all branches length 1
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let t_1 = from_vec(&[0, 0, 0, 1]);
+    let gen_data = create_dummy_gendata(10, &t_1, &p.get_matrix(), &rates);
+    // ((2,(3,1)4)5,0)6 with lengths moved so leaf 0 and branch 4-5 match the first tree
+    let mut t_2 = from_vec(&[0, 0, 1, 1]);
+    t_2.nodes[0].set_branchlen(0.5);
+    t_2.nodes[5].set_branchlen(0.5);
+    t_2.nodes[4].set_branchlen(2.0);
+    let gen_data_2 = create_internal_data(gen_data.clone(), &t_2, &p.get_matrix(), &rates);
+    assert!(
+        (t_1.likelihood(&gen_data, &p, &rates) - t_2.likelihood(&gen_data_2, &p, &rates)).abs()
+            < 1e-8
+    );
+
+    let mut p_emp = Gtr::default();
+    p_emp.set_freqs(empirical_base_freqs(&[b"AACG".to_vec(), b"TAY-".to_vec()]));
+    let expected = [3.0 / 7.0, 1.5 / 7.0, 1.0 / 7.0, 1.5 / 7.0];
+    for (f, e) in p_emp.get_freqs().iter().zip(expected.iter()) {
+        assert!((f - e).abs() < 1e-12);
+    }
+}
+
+#[test]
+fn rate_matrix_normalisation() {
+    let params = vec![3.0, 6.0, 1.5, 2.4, 9.0, 3.0, 0.1, 0.2, 0.3, 0.4];
+    let mut p = Gtr::default();
+    assert!(p.get_normalised());
+    assert!((mean_rate(&p.get_matrix(), p.get_freqs()) - 1.0).abs() < 1e-12);
+    p.update_params(params.clone());
+    assert!((mean_rate(&p.get_matrix(), p.get_freqs()) - 1.0).abs() < 1e-12);
+
+    // Without normalisation the rate depends on the parameters
+    let mut raw = Gtr::default();
+    raw.set_normalised(false);
+    raw.update_params(params);
+    let raw_rate = mean_rate(&raw.get_matrix(), raw.get_freqs());
+    assert!((raw_rate - 1.0).abs() > 0.1);
+    assert!((raw.get_matrix() / raw_rate - p.get_matrix()).norm() < 1e-12);
+
+    // Proposals keep the normalisation mode of the current matrix
+    for _ in 0..10 {
+        let new = p.matrix_move();
+        assert!(new.get_normalised());
+        assert!((mean_rate(&new.get_matrix(), new.get_freqs()) - 1.0).abs() < 1e-12);
+        assert!(!raw.matrix_move().get_normalised());
+    }
+
+    let mut jc = Jc69::default();
+    jc.update_params(vec![0.3]);
+    jc.update_matrix();
+    assert!((mean_rate(&jc.get_matrix(), jc.get_freqs()) - 1.0).abs() < 1e-12);
+}
+
+// Exchangeabilities (AC, AG, AT, CG, CT, GT) of a reversible rate matrix
+fn exchangeabilities<R: RateMatrix>(p: &R) -> [f64; 6] {
+    let q = p.get_matrix();
+    let f = p.get_freqs();
+    [
+        q[(0, 1)] / f[1],
+        q[(0, 2)] / f[2],
+        q[(0, 3)] / f[3],
+        q[(1, 2)] / f[2],
+        q[(1, 3)] / f[3],
+        q[(2, 3)] / f[3],
+    ]
+}
+
+fn assert_same_as_gtr<R: RateMatrix>(p: &R, rates: [f64; 6], freqs: [f64; 4]) {
+    let mut gtr = Gtr::default();
+    gtr.update_params([rates.as_slice(), freqs.as_slice()].concat());
+    assert!((p.get_matrix() - gtr.get_matrix()).norm() < 1e-12);
+    assert!((mean_rate(&p.get_matrix(), p.get_freqs()) - 1.0).abs() < 1e-12);
+}
+
+// Checks rate classes given by the code in each position are tied, e.g. 010010 for K80
+fn assert_tied_rates<R: RateMatrix>(p: &R, code: [usize; 6]) {
+    let r = exchangeabilities(p);
+    for i in 0..6 {
+        for j in 0..6 {
+            if code[i] == code[j] {
+                assert!((r[i] - r[j]).abs() < 1e-10 * r[i]);
+            }
+        }
+    }
+}
+
+#[test]
+fn nested_substitution_models() {
+    let freqs = [0.1, 0.2, 0.3, 0.4];
+    let equal = [0.25; 4];
+
+    let mut k80 = K80::default();
+    k80.update_params(vec![2.5]);
+    assert_same_as_gtr(&k80, [1.0, 2.5, 1.0, 1.0, 2.5, 1.0], equal);
+
+    let mut f81 = F81::default();
+    f81.set_freqs(freqs);
+    assert_same_as_gtr(&f81, [1.0; 6], freqs);
+
+    let mut hky = Hky85::default();
+    hky.update_params(vec![2.5, 0.1, 0.2, 0.3, 0.4]);
+    assert_same_as_gtr(&hky, [1.0, 2.5, 1.0, 1.0, 2.5, 1.0], freqs);
+
+    let mut tn93 = Tn93::default();
+    tn93.update_params(vec![2.5, 4.0, 0.1, 0.2, 0.3, 0.4]);
+    assert_same_as_gtr(&tn93, [1.0, 2.5, 1.0, 1.0, 4.0, 1.0], freqs);
+
+    let mut tim = Tim::default();
+    tim.update_params(vec![2.5, 0.5, 4.0, 0.1, 0.2, 0.3, 0.4]);
+    assert_same_as_gtr(&tim, [1.0, 2.5, 0.5, 0.5, 4.0, 1.0], freqs);
+
+    let mut tvm = Tvm::default();
+    tvm.update_params(vec![0.8, 2.5, 0.5, 1.5, 0.1, 0.2, 0.3, 0.4]);
+    assert_same_as_gtr(&tvm, [0.8, 2.5, 0.5, 1.5, 2.5, 1.0], freqs);
+
+    let mut sym = Sym::default();
+    sym.update_params(vec![0.8, 2.5, 0.5, 1.5, 4.0, 1.2]);
+    assert_same_as_gtr(&sym, [0.8, 2.5, 0.5, 1.5, 4.0, 1.2], equal);
+
+    // Proposals stay within each model
+    for _ in 0..10 {
+        assert_tied_rates(&k80.matrix_move(), [0, 1, 0, 0, 1, 0]);
+        assert_eq!(k80.matrix_move().get_freqs(), equal);
+        assert_tied_rates(&f81.matrix_move(), [0, 0, 0, 0, 0, 0]);
+        assert_tied_rates(&hky.matrix_move(), [0, 1, 0, 0, 1, 0]);
+        assert_tied_rates(&tn93.matrix_move(), [0, 1, 0, 0, 2, 0]);
+        assert_tied_rates(&tim.matrix_move(), [0, 1, 2, 2, 3, 0]);
+        assert_tied_rates(&tvm.matrix_move(), [0, 1, 2, 3, 1, 4]);
+        assert_eq!(sym.matrix_move().get_freqs(), equal);
+        let new = tvm.matrix_move();
+        assert!((mean_rate(&new.get_matrix(), new.get_freqs()) - 1.0).abs() < 1e-12);
+    }
+}
+
+#[test]
+fn model_selection() {
+    let (x, fx) = brent_maximise(|x| -(x - 1.5).powi(2) + 2.0, -10.0, 10.0, 1e-8);
+    assert!((x - 1.5).abs() < 1e-6 && (fx - 2.0).abs() < 1e-10);
+
+    let (aic, aicc, bic) = information_criteria(-100.0, 3, 50);
+    assert_eq!(aic, 206.0);
+    assert!((aicc - (206.0 + 24.0 / 46.0)).abs() < 1e-12);
+    assert!((bic - (3.0 * 50.0_f64.ln() + 200.0)).abs() < 1e-12);
+
+    // Six sequences and 400 sites of the test alignment
+    let seqs: Vec<Vec<u8>> = read_alignment("tests/test_files_in/listeria0.aln")
+        .1
+        .iter()
+        .take(6)
+        .map(|seq| seq[..400].to_vec())
+        .collect();
+    let t = from_vec(&random_vector(6));
+    let fits = select_model(
+        &seqs,
+        &t,
+        Ascertainment::None,
+        Kernel::Log,
+        1,
+        Some(2),
+        Criterion::Bic,
+    );
+    assert_eq!(fits.len(), 36);
+    assert!(fits.windows(2).all(|f| f[0].bic <= f[1].bic));
+
+    // Models nested in another fit no better, up to the optimiser tolerance
+    let ll = |name: &str| fits.iter().find(|f| f.name == name).unwrap().log_likelihood;
+    for (simple, general) in [
+        ("JC69", "K80"),
+        ("JC69", "F81"),
+        ("K80", "HKY85"),
+        ("HKY85", "TN93"),
+        ("TN93", "TIM"),
+        ("TIM", "GTR"),
+        ("TVM", "GTR"),
+        ("SYM", "GTR"),
+        ("HKY85", "HKY85+G2"),
+        ("HKY85+G2", "HKY85+I+G2"),
+    ] {
+        assert!(ll(simple) < ll(general) + 0.1, "{simple} {general}");
+    }
+    let gtr = fits.iter().find(|f| f.name == "GTR+I+G2").unwrap();
+    assert_eq!(gtr.model, Model::Gtr);
+    assert_eq!(gtr.n_params, 8 + 2 + 9);
+    assert!(gtr.rates.get_pinv() > 0.0 && gtr.rates.get_n_cats() == 2);
+}
+
+#[test]
+fn eigen_transition_matrices() {
+    let mut p = Gtr::default();
+    p.update_params(vec![3.0, 6.0, 1.5, 2.4, 9.0, 3.0, 0.1, 0.2, 0.3, 0.4]);
+    let q = p.get_matrix();
+    let eigen = eigen_decomposition(&q).unwrap();
+    assert!((eigen.eigenvectors * eigen.inverse - na::Matrix4::identity()).norm() < 1e-12);
+
+    for t in [0.0, 1e-6, 0.01, 0.3, 2.0, 50.0] {
+        let expected = na::Matrix::exp(&(q * t));
+        assert!((eigen.transition_matrix(t) - expected).norm() < 1e-10);
+        let first = matrix_exp(&q, t);
+        assert!((first - expected).norm() < 1e-10);
+        // A second call is answered from the cache
+        let hits = || MATRIX_EXP.lock().unwrap().cache_hits().unwrap();
+        let before = hits();
+        assert_eq!(matrix_exp(&q, t), first);
+        assert!(hits() > before);
+    }
+
+    // Irreversible rate matrices use the matrix exponential
+    let q = na::Matrix4::new(
+        -0.6, 0.1, 0.2, 0.3, 0.3, -0.6, 0.2, 0.1, 0.1, 0.1, -0.3, 0.1, 0.5, 0.2, 0.1, -0.8,
+    );
+    assert!(eigen_decomposition(&q).is_none());
+    assert_eq!(matrix_exp(&q, 0.5), na::Matrix::exp(&(q * 0.5)));
+}
+
+#[test]
+fn linear_kernel_matches_log() {
+    let p = Gtr::default();
+    let rates = SiteRates::new(4, 0.5, 0.2);
+    let t = from_vec(&random_vector(28));
+    let aln = "tests/test_files_in/listeria0.aln";
+    let log_data = create_genetic_data(
+        aln,
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Log,
+        1,
+    );
+    let lin_data = create_genetic_data(
+        aln,
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Linear,
+        1,
+    );
+    let ll = t.likelihood(&log_data, &p, &rates);
+    assert!((t.likelihood(&lin_data, &p, &rates) - ll).abs() < 1e-6);
+    for node in t.postorder_notips(t.get_root()) {
+        let diff = &log_data.log_partials(node.get_id()) - &lin_data.log_partials(node.get_id());
+        assert!(diff.iter().all(|d| d.is_nan() || d.abs() < 1e-8));
+    }
+
+    // Moves update the scalers along with the partials
+    let mut ts = TreeState {
+        top: t,
+        mat: p,
+        rates: rates.clone(),
+        likelihood: ll,
+    };
+    let mut lin_data = lin_data;
+    let new_vec = random_vector(28);
+    let log_ts = apply_move(
+        TreeState {
+            top: ts.top.clone(),
+            mat: p,
+            rates: rates.clone(),
+            likelihood: ll,
+        },
+        ExactMove {
+            target_vector: new_vec.clone(),
+        },
+        always_accept,
+        &mut log_data.clone(),
+    );
+    ts = apply_move(
+        ts,
+        ExactMove {
+            target_vector: new_vec,
+        },
+        always_accept,
+        &mut lin_data,
+    );
+    assert!((ts.likelihood - log_ts.likelihood).abs() < 1e-6);
+    assert!((ts.top.likelihood(&lin_data, &p, &rates) - ts.likelihood).abs() < 1e-6);
+
+    // Long branches over many leaves underflow without scaling
+    let n_leaves = 600;
+    let mut t_long = from_vec(&random_vector(n_leaves));
+    for node in t_long.nodes.iter_mut() {
+        node.set_branchlen(5.0);
+    }
+    let mut rng = rand::thread_rng();
+    let states: Vec<usize> = (0..n_leaves * 5).map(|_| rng.gen_range(0..4)).collect();
+    let leaves = ndarray::Array3::from_shape_fn((2 * n_leaves - 1, 4 * 5, 4), |(i, k, j)| {
+        if i < n_leaves && states[i * 5 + k % 5] == j {
+            0.0
+        } else {
+            f64::NEG_INFINITY
+        }
+    });
+    let log_data = create_internal_data(
+        GeneticData::new(leaves, n_leaves, 4),
+        &t_long,
+        &p.get_matrix(),
+        &rates,
+    );
+    // Only the linear kernel stores scalers
+    assert!(log_data.scalers.is_empty());
+    let mut lin_data = log_data.clone();
+    lin_data.set_kernel(Kernel::Linear);
+    let lin_data = create_internal_data(lin_data, &t_long, &p.get_matrix(), &rates);
+    let root = t_long.get_root().get_id();
+    assert!(lin_data.scalers.row(root).iter().all(|s| *s < f64::MIN_EXP));
+    let ll = t_long.likelihood(&log_data, &p, &rates);
+    assert!(ll.is_finite());
+    assert!((t_long.likelihood(&lin_data, &p, &rates) - ll).abs() < 1e-6 * ll.abs());
+}
+
+#[test]
+fn threaded_likelihood_is_deterministic() {
+    let p = Gtr::default();
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let t = from_vec(&random_vector(28));
+    let new_vec = random_vector(28);
+    let aln = "tests/test_files_in/listeria0.aln";
+
+    for kernel in [Kernel::Log, Kernel::Linear] {
+        let serial = create_genetic_data(
+            aln,
+            &t,
+            &p.get_matrix(),
+            &rates,
+            Ascertainment::None,
+            kernel,
+            1,
+        );
+        let ll = t.likelihood(&serial, &p, &rates);
+        for threads in [2, 3, 7] {
+            let mut data = create_genetic_data(
+                aln,
+                &t,
+                &p.get_matrix(),
+                &rates,
+                Ascertainment::None,
+                kernel,
+                threads,
+            );
+            assert_eq!(data.partials, serial.partials);
+            assert_eq!(data.scalers, serial.scalers);
+            assert_eq!(t.likelihood(&data, &p, &rates), ll);
+
+            // Moves give the same likelihood whatever the number of threads
+            let mut serial_data = serial.clone();
+            let ts = |top: &Topology| TreeState {
+                top: from_vec(&top.tree_vec),
+                mat: p,
+                rates: rates.clone(),
+                likelihood: ll,
+            };
+            let mv = || ExactMove {
+                target_vector: new_vec.clone(),
+            };
+            let serial_ts = apply_move(ts(&t), mv(), always_accept, &mut serial_data);
+            let threaded_ts = apply_move(ts(&t), mv(), always_accept, &mut data);
+            assert_eq!(serial_ts.likelihood, threaded_ts.likelihood);
+            assert_eq!(data.partials, serial_data.partials);
+        }
+    }
+}
+
+#[test]
+fn simd_kernel_matches_scalar() {
+    let p = Gtr::default();
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let t = from_vec(&random_vector(28));
+    let gen_data = create_genetic_data(
+        "tests/test_files_in/listeria0.aln",
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Linear,
+        1,
+    );
+
+    for node in t.postorder_notips(t.get_root()) {
+        let (l, r) = (node.get_lchild().unwrap(), node.get_rchild().unwrap());
+        let child = |i: usize| {
+            (
+                gen_data.partials.slice(s![i, .., ..]),
+                gen_data.scalers.slice(s![i, ..]),
+            )
+        };
+        let ml = transition_matrices(&p.get_matrix(), t.nodes[l].get_branchlen(), &rates);
+        let mr = transition_matrices(&p.get_matrix(), t.nodes[r].get_branchlen(), &rates);
+        let (scalar, scalar_scalers) = node_likelihood_scaled_scalar(child(l), child(r), &ml, &mr);
+
+        match node_likelihood_scaled_simd(child(l), child(r), &ml, &mr) {
+            Some((vector, vector_scalers)) => {
+                assert!(simd::available());
+                // Rounding can decide differently whether a row at the threshold is
+                // rescaled, so compare in log space
+                let vector = to_log_space(vector.view(), vector_scalers.view());
+                let scalar = to_log_space(scalar.view(), scalar_scalers.view());
+                let diff = (&vector - &scalar).mapv(f64::abs);
+                assert!(diff
+                    .iter()
+                    .zip(scalar.iter())
+                    .all(|(d, s)| *d <= 1e-12 * s.abs().max(1.0)));
+            }
+            None => assert!(!simd::available()),
+        }
+    }
+}
+
+#[test]
+fn single_precision_partials() {
+    let p = Gtr::default();
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let t = from_vec(&random_vector(28));
+    let aln = "tests/test_files_in/listeria0.aln";
+    let data = |kernel| {
+        create_genetic_data(
+            aln,
+            &t,
+            &p.get_matrix(),
+            &rates,
+            Ascertainment::None,
+            kernel,
+            1,
+        )
+    };
+    let log_data = data(Kernel::Log);
+    let mut f32_data = data(Kernel::LinearF32);
+    assert_eq!(f32_data.partials.len(), 0);
+    assert_eq!(f32_data.partials_f32.dim(), log_data.partials.dim());
+    // With a four byte exponent per row, the single precision partials and their
+    // scalers take 5/8 of the memory of the double precision partials
+    let bytes = |data: &GeneticData| {
+        data.partials.len() * size_of::<f64>()
+            + data.partials_f32.len() * size_of::<f32>()
+            + data.scalers.len() * size_of::<i32>()
+    };
+    assert_eq!(8 * bytes(&f32_data), 5 * bytes(&log_data));
+
+    let ll = t.likelihood(&log_data, &p, &rates);
+    let ll_f32 = t.likelihood(&f32_data, &p, &rates);
+    assert!((ll_f32 - ll).abs() < 1e-6 * ll.abs());
+
+    // Partials are rounded the same way in moves as when stored
+    let ts = TreeState {
+        top: t,
+        mat: p,
+        rates: rates.clone(),
+        likelihood: ll_f32,
+    };
+    let ts = apply_move(
+        ts,
+        ExactMove {
+            target_vector: random_vector(28),
+        },
+        always_accept,
+        &mut f32_data,
+    );
+    let recalculated = create_internal_data(f32_data, &ts.top, &p.get_matrix(), &rates);
+    assert_eq!(ts.top.likelihood(&recalculated, &p, &rates), ts.likelihood);
+
+    // Converting back to double precision
+    let mut converted = recalculated.clone();
+    converted.set_kernel(Kernel::Linear);
+    let converted = create_internal_data(converted, &ts.top, &p.get_matrix(), &rates);
+    assert!((ts.top.likelihood(&converted, &p, &rates) - ts.likelihood).abs() < 1e-6 * ll.abs());
+}
+
+#[test]
+fn site_likelihoods() {
+    let p = Hky85::default();
+    let rates = SiteRates::new(4, 0.5, 0.0);
+    let t = from_vec(&random_vector(28));
+    let file = "tests/test_files_in/listeria0.aln";
+    let (_, seqs) = read_alignment(file);
+    let n_sites = seqs[0].len();
+
+    // The Lewis correction is spread over the sites, so both sum to the likelihood
+    let dir = TempDir::new().unwrap();
+    let snp_file = write_fasta(
+        &dir,
+        "bactrees_sitelh_snps.fasta",
+        &variable_sites(&seqs, 500),
+    );
+    for (file, asc) in [
+        (file, Ascertainment::None),
+        (&snp_file, Ascertainment::Lewis),
+    ] {
+        let gen_data = create_genetic_data(file, &t, &p.get_matrix(), &rates, asc, Kernel::Log, 1);
+        let site_ll = t.site_likelihoods(&gen_data, &p, &rates);
+        assert_eq!(site_ll.len(), gen_data.n_sites());
+        let total = t.likelihood(&gen_data, &p, &rates);
+        assert!((site_ll.iter().sum::<f64>() - total).abs() < 1e-6 * total.abs());
+    }
+
+    // Header with the number of trees and sites, then one line per tree
+    let gen_data = create_genetic_data(
+        file,
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Log,
+        1,
+    );
+    let site_ll = t.site_likelihoods(&gen_data, &p, &rates);
+    let dir = TempDir::new().unwrap();
+    let out = dir.path().join("bactrees_test.sitelh");
+    write_site_likelihoods(out.to_str().unwrap(), &[site_ll.clone(), site_ll.clone()]).unwrap();
+    let contents = std::fs::read_to_string(out).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(
+        lines[0].split_whitespace().collect::<Vec<_>>(),
+        ["2", &n_sites.to_string()]
+    );
+    let (name, values) = lines[2].split_once('\t').unwrap();
+    assert_eq!(name, "tr2");
+    let values: Vec<f64> = values.split(' ').map(|v| v.parse().unwrap()).collect();
+    assert_eq!(values.len(), n_sites);
+    assert!((values[7] - site_ll[7]).abs() < 1e-6);
+}
+
+#[test]
+fn marginal_ancestral_states() {
+    // Compare to the posterior from summing over every assignment of internal states
+    let mut t = from_vec(&random_vector(4));
+    for (i, node) in t.nodes.iter_mut().enumerate() {
+        node.set_branchlen(0.1 + 0.2 * i as f64);
+    }
+    let p = Hky85::default();
+    let rates = SiteRates::new(2, 0.5, 0.2);
+    let gen_data = create_dummy_gendata(20, &t, &p.get_matrix(), &rates);
+    let states = marginal_ancestral(&t, &gen_data, &p, &rates);
+
+    let internal = states.nodes();
+    assert_eq!(internal.len(), 3);
+    let freqs = p.get_freqs();
+    let root = t.get_root().get_id();
+    let weights = rates.get_weights();
+    for i in 0..gen_data.n_patterns() {
+        let mut expected = ndarray::Array2::<f64>::zeros((t.nodes.len(), 4));
+        for (c, w) in weights.iter().enumerate() {
+            for assignment in 0..64 {
+                let state = |node: usize| {
+                    let k = internal.iter().position(|n| *n == node).unwrap();
+                    (assignment >> (2 * k)) & 3
+                };
+                let mut prob = w * freqs[state(root)];
+                for node in t.nodes.iter().filter(|n| n.get_id() != root) {
+                    let m =
+                        matrix_exp(&p.get_matrix(), node.get_branchlen() * rates.get_rates()[c]);
+                    let from = state(node.get_parent().unwrap());
+                    prob *= if internal.contains(&node.get_id()) {
+                        m[(from, state(node.get_id()))]
+                    } else {
+                        let leaf = gen_data.partials.slice(s![node.get_id(), c * 20 + i, ..]);
+                        (0..4).map(|z| m[(from, z)] * leaf[z].exp()).sum()
+                    };
+                }
+                for node in &internal {
+                    expected[[*node, state(*node)]] += prob;
+                }
+            }
+        }
+        for node in &internal {
+            for y in 0..4 {
+                expected[[*node, y]] +=
+                    rates.get_pinv() * freqs[y] * gen_data.invariant[[i, y]].exp();
+            }
+            let total: f64 = expected.row(*node).sum();
+            for y in 0..4 {
+                let posterior = states.posteriors[node][[i, y]];
+                assert!((posterior - expected[[*node, y]] / total).abs() < 1e-10);
+            }
+        }
+    }
+
+    // Identical sequences are reconstructed as the leaf states
+    let seqs: Vec<Vec<u8>> = (0..6).map(|_| b"ACGTTGCAAC".to_vec()).collect();
+    let dir = TempDir::new().unwrap();
+    let file = write_fasta(&dir, "bactrees_ancestral.fasta", &seqs);
+    let t = from_vec(&random_vector(6));
+    let gen_data = create_genetic_data(
+        &file,
+        &t,
+        &p.get_matrix(),
+        &SiteRates::default(),
+        Ascertainment::None,
+        Kernel::Linear,
+        1,
+    );
+    let states = marginal_ancestral(&t, &gen_data, &p, &SiteRates::default());
+    for node in states.nodes() {
+        assert_eq!(gen_data.expand_patterns(&states.sequence(node)), seqs[0]);
+    }
+}
+
+#[test]
+fn joint_ancestral_states() {
+    // The reconstruction has the highest probability of any assignment of states
+    let mut t = from_vec(&random_vector(4));
+    for (i, node) in t.nodes.iter_mut().enumerate() {
+        node.set_branchlen(0.1 + 0.2 * i as f64);
+    }
+    let p = Hky85::default();
+    let rates = SiteRates::default();
+    let gen_data = create_dummy_gendata(20, &t, &p.get_matrix(), &rates);
+    let states = joint_ancestral(&t, &gen_data, &p, &rates);
+
+    let freqs = p.get_freqs();
+    let root = t.get_root().get_id();
+    let n_nodes = t.count_leaves() * 2 - 1;
+    let log_prob = |i: usize, state: &dyn Fn(usize) -> usize| {
+        let mut ll = freqs[state(root)].ln();
+        for node in t.nodes[..n_nodes].iter() {
+            if let Some(parent) = node.get_parent() {
+                let m = matrix_exp(&p.get_matrix(), node.get_branchlen());
+                ll += m[(state(parent), state(node.get_id()))].ln();
+            }
+            if node.get_lchild().is_none() {
+                ll += gen_data.partials[[node.get_id(), i, state(node.get_id())]];
+            }
+        }
+        ll
+    };
+    for i in 0..20 {
+        let best = (0..4_usize.pow(n_nodes as u32))
+            .map(|a| log_prob(i, &|node| (a >> (2 * node)) & 3))
+            .fold(f64::NEG_INFINITY, f64::max);
+        let base = |node: usize| {
+            let b = states.states[node][i];
+            [b'A', b'C', b'G', b'T']
+                .iter()
+                .position(|x| *x == b)
+                .unwrap()
+        };
+        assert!((log_prob(i, &base) - best).abs() < 1e-10);
+    }
+
+    // A single change in one sequence is placed on the branch above its leaf
+    let mut seqs: Vec<Vec<u8>> = (0..6).map(|_| b"ACGTTGCAAC".to_vec()).collect();
+    seqs[4][2] = b'T';
+    let dir = TempDir::new().unwrap();
+    let file = write_fasta(&dir, "bactrees_joint.fasta", &seqs);
+    // Leaf 4 is not a child of the root, where the change could equally be placed on
+    // its sibling's branch
+    let t = from_vec(&[0; 6]);
+    let gen_data = create_genetic_data(
+        &file,
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Log,
+        1,
+    );
+    let states = joint_ancestral(&t, &gen_data, &p, &rates);
+    let mutations = states.branch_mutations(&t, &gen_data);
+    assert_eq!(mutations.len(), 10);
+    for (node, m) in mutations.iter() {
+        if *node == 4 {
+            let expected = Mutation {
+                site: 2,
+                from: b'G',
+                to: b'T',
+            };
+            assert_eq!(m, &vec![expected]);
+        } else {
+            assert!(m.is_empty());
+        }
+    }
+
+    let snp_counts = mutations.iter().map(|(n, m)| (*n, m.len())).collect();
+    let newick = t.get_newick_snps(&snp_counts);
+    assert_eq!(newick.matches("[&snps=0]").count(), 10);
+    assert!(newick.contains("4:1[&snps=1]"));
+    assert_eq!(
+        newick.replace("[&snps=0]", "").replace("[&snps=1]", ""),
+        t.get_newick()
+    );
+}
+
+#[test]
+fn homoplasy_detection() {
+    // (1,(2,(3,(4,(5,(6,(7,0)))))))
+    let mut t = from_vec(&[0; 8]);
+    for node in t.nodes.iter_mut() {
+        node.set_branchlen(0.05);
+    }
+    // Two distant leaves share a base at site 1, so it arose twice
+    let mut seqs: Vec<Vec<u8>> = (0..8).map(|_| b"ACGTTGCAAC".to_vec()).collect();
+    seqs[0][1] = b'T';
+    seqs[4][1] = b'T';
+    // A single change at site 5, and a gap that needs no change at site 7
+    seqs[4][5] = b'A';
+    seqs[2][7] = b'-';
+    let dir = TempDir::new().unwrap();
+    let file = write_fasta(&dir, "bactrees_homoplasy.fasta", &seqs);
+
+    let p = Hky85::default();
+    let rates = SiteRates::default();
+    let gen_data = create_genetic_data(
+        &file,
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Log,
+        1,
+    );
+    let ts = TreeState {
+        likelihood: t.likelihood(&gen_data, &p, &rates),
+        top: t,
+        mat: p,
+        rates,
+    };
+    let report = homoplasy_report(&ts, &gen_data);
+    assert_eq!(report.len(), 2);
+
+    assert_eq!(report[0].site, 1);
+    assert!(report[0].is_homoplastic());
+    assert_eq!(report[0].min_changes, 1);
+    let branches: Vec<usize> = report[0].branches.iter().map(|(node, _)| *node).collect();
+    assert_eq!(branches, vec![0, 4]);
+    assert!(report[0]
+        .branches
+        .iter()
+        .all(|(_, m)| (m.from, m.to) == (b'C', b'T')));
+    assert_eq!(report[0].consistency_index(), 0.5);
+
+    assert_eq!(report[1].site, 5);
+    assert!(!report[1].is_homoplastic());
+    assert_eq!(report[1].consistency_index(), 1.0);
+    assert_eq!(ensemble_consistency_index(&report), Some(2.0 / 3.0));
+    assert_eq!(ensemble_consistency_index(&[]), None);
+}
+
+#[test]
+fn branch_length_derivatives() {
+    let t = from_vec(&random_vector(28));
+    let p = Hky85::default();
+    let rates = SiteRates::new(4, 0.5, 0.2);
+    let root = t.get_root();
+    let (child, sibling) = (root.get_lchild().unwrap(), root.get_rchild().unwrap());
+    let log_freqs = p.get_freqs().map(f64::ln);
+    let eigen = eigen_decomposition(&p.get_matrix()).unwrap();
+
+    // Sites as they are, and only the variable sites with a correction
+    let (_, seqs) = read_alignment("tests/test_files_in/listeria0.aln");
+    let dir = TempDir::new().unwrap();
+    let snps = variable_sites(&seqs, 500);
+    let snp_file = write_fasta(&dir, "bactrees_derivative_snps.fasta", &snps);
+    for (file, asc) in [
+        ("tests/test_files_in/listeria0.aln", Ascertainment::None),
+        (snp_file.as_str(), Ascertainment::Lewis),
+        (snp_file.as_str(), Ascertainment::Felsenstein(500.0)),
+        (
+            snp_file.as_str(),
+            Ascertainment::Stamatakis([100.0, 50.0, 80.0, 120.0]),
+        ),
+    ] {
+        let gen_data =
+            create_genetic_data(file, &t, &p.get_matrix(), &rates, asc, Kernel::Linear, 1);
+        let outside = ndarray::Array2::from_shape_fn((gen_data.n_rows(), 4), |(_, j)| log_freqs[j]);
+        let p_sibling = transition_matrices(&p.get_matrix(), 1.0, &rates);
+        let above = above_branch(
+            outside.view(),
+            gen_data.log_partials(sibling).view(),
+            &p_sibling,
+        );
+        let below = gen_data.log_partials(child);
+        let derivatives = |bl: f64| {
+            branch_derivatives(
+                above.view(),
+                below.view(),
+                &eigen,
+                bl,
+                &gen_data,
+                &rates,
+                p.get_freqs(),
+            )
+        };
+
+        let (ll, d1, d2) = derivatives(1.0);
+        assert!((ll - t.likelihood(&gen_data, &p, &rates)).abs() < 1e-6 * ll.abs());
+        let h = 1e-4;
+        let (ll_up, d1_up, _) = derivatives(1.0 + h);
+        let (ll_down, d1_down, _) = derivatives(1.0 - h);
+        assert!((d1 - (ll_up - ll_down) / (2.0 * h)).abs() < 1e-4 * d1.abs().max(1.0));
+        assert!((d2 - (d1_up - d1_down) / (2.0 * h)).abs() < 1e-4 * d2.abs().max(1.0));
+    }
+}
+
+#[test]
+fn branch_length_optimisation() {
+    let seqs: Vec<Vec<u8>> = read_alignment("tests/test_files_in/listeria0.aln")
+        .1
+        .iter()
+        .map(|seq| seq[..2000].to_vec())
+        .collect();
+    let dir = TempDir::new().unwrap();
+    let file = write_fasta(&dir, "bactrees_branch_lengths.fasta", &seqs);
+    let t = from_vec(&random_vector(28));
+    let p = Hky85::default();
+    let rates = SiteRates::new(2, 0.5, 0.0);
+    let mut gen_data = create_genetic_data(
+        &file,
+        &t,
+        &p.get_matrix(),
+        &rates,
+        Ascertainment::None,
+        Kernel::Linear,
+        1,
+    );
+    let start_ll = t.likelihood(&gen_data, &p, &rates);
+    let ts = TreeState {
+        top: t,
+        mat: p,
+        rates: rates.clone(),
+        likelihood: start_ll,
+    };
+    let ts = optimise_branch_lengths(ts, &mut gen_data, 1e-6);
+    assert!(ts.likelihood > start_ll);
+
+    // The partials are left matching the new branch lengths
+    let recalculated = create_internal_data(gen_data.clone(), &ts.top, &p.get_matrix(), &rates);
+    assert!((ts.top.likelihood(&recalculated, &p, &rates) - ts.likelihood).abs() < 1e-6);
+
+    // Changing any branch by 1% makes the tree less likely
+    for node in ts.top.postorder(ts.top.get_root()) {
+        if node.get_parent().is_none() || node.get_branchlen() < 1e-6 {
+            continue;
+        }
+        for factor in [0.99, 1.01] {
+            let mut top = ts.top.clone();
+            top.nodes[node.get_id()].set_branchlen(node.get_branchlen() * factor);
+            let gen_data = create_internal_data(gen_data.clone(), &top, &p.get_matrix(), &rates);
+            assert!(top.likelihood(&gen_data, &p, &rates) < ts.likelihood + 1e-6);
+        }
+    }
+}
+
+// Random tree with random data for 50 sites, with two gamma rate categories
+fn dummy_tree_state<R: RateMatrix + Default>(n_leaves: usize) -> (TreeState<R>, GeneticData) {
+    let t = from_vec(&random_vector(n_leaves));
+    let p = R::default();
+    let rates = SiteRates::new(2, 0.5, 0.0);
+    let gen_data = create_dummy_gendata(50, &t, &p.get_matrix(), &rates);
+    let ts = TreeState {
+        likelihood: t.likelihood(&gen_data, &p, &rates),
+        top: t,
+        mat: p,
+        rates,
+    };
+    (ts, gen_data)
+}
+
+#[test]
+fn branch_length_moves() {
+    let (mut ts, mut gen_data) = dummy_tree_state::<Hky85>(12);
+    let (p, rates) = (ts.mat, ts.rates.clone());
+
+    // Only the parents of changed branches are recalculated, then their ancestors
+    let leaf = 3;
+    let internal = ts.top.nodes[leaf].get_parent().unwrap();
+    let mv = BranchMove {
+        indices: vec![leaf, internal],
+        sigma: 0.5,
+    };
+    let (new_top, _, changes) = mv.generate(&ts);
+    let new_top = new_top.unwrap();
+    let mut expected = vec![internal, ts.top.nodes[internal].get_parent().unwrap()];
+    expected.sort();
+    assert_eq!(changes, Some(expected));
+    for node in ts.top.nodes.iter() {
+        let changed = node.get_branchlen() != new_top.nodes[node.get_id()].get_branchlen();
+        assert_eq!(changed, [leaf, internal].contains(&node.get_id()));
+    }
+
+    // The likelihood after an accepted move is the same as recalculating every node
+    for _ in 0..5 {
+        let old_top = ts.top.clone();
+        ts = apply_move(
+            ts,
+            RandomBranchMove { n: 3, sigma: 0.3 },
+            always_accept,
+            &mut gen_data,
+        );
+        let n_changed = (0..old_top.nodes.len())
+            .filter(|i| old_top.nodes[*i].get_branchlen() != ts.top.nodes[*i].get_branchlen())
+            .count();
+        assert_eq!(n_changed, 3);
+        let full = create_internal_data(gen_data.clone(), &ts.top, &p.get_matrix(), &rates);
+        assert!((ts.likelihood - ts.top.likelihood(&full, &p, &rates)).abs() < 1e-8);
+    }
+
+    // A rejected move keeps the current tree, likelihood and partials
+    let (old_top, old_ll, old_partials) =
+        (ts.top.clone(), ts.likelihood, gen_data.partials.clone());
+    let mv = RandomBranchMove { n: 5, sigma: 1.0 };
+    ts = apply_move(ts, mv, |_, _| false, &mut gen_data);
+    assert_eq!(ts.likelihood, old_ll);
+    assert_eq!(gen_data.partials, old_partials);
+    for (a, b) in ts.top.nodes.iter().zip(old_top.nodes.iter()) {
+        assert_eq!(a.get_branchlen(), b.get_branchlen());
+    }
+}
+
+#[test]
+fn local_matrix_moves() {
+    // Each move changes one exchangeability or two frequencies, keeping the model
+    let mut gtr = Gtr::default();
+    gtr.update_params(vec![1.0, 2.0, 0.5, 1.5, 3.0, 1.0, 0.1, 0.2, 0.3, 0.4]);
+    for _ in 0..50 {
+        let new = local_matrix_move(&gtr, 0.5, 0.05);
+        let (old_params, new_params) = (gtr.get_params(), new.get_params());
+        let rates_changed = (0..6).filter(|i| old_params[*i] != new_params[*i]).count();
+        let freqs_changed = (6..10).filter(|i| old_params[*i] != new_params[*i]).count();
+        assert!(matches!((rates_changed, freqs_changed), (1, 0) | (0, 2)));
+        assert!((new.get_freqs().iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(new.get_freqs().iter().all(|f| *f > 0.0));
+        assert!((mean_rate(&new.get_matrix(), new.get_freqs()) - 1.0).abs() < 1e-10);
+        gtr = new;
+    }
+    for _ in 0..20 {
+        assert_tied_rates(
+            &local_matrix_move(&Tn93::default(), 0.5, 0.05),
+            [0, 1, 0, 0, 2, 0],
+        );
+        assert_tied_rates(
+            &local_matrix_move(&Tim::default(), 0.5, 0.05),
+            [0, 1, 2, 2, 3, 0],
+        );
+        assert_eq!(
+            local_matrix_move(&K80::default(), 0.5, 0.05).get_freqs(),
+            [0.25; 4]
+        );
+    }
+    let jc = local_matrix_move(&Jc69::default(), 0.5, 0.05);
+    assert_eq!(jc.get_matrix(), Jc69::default().get_matrix());
+
+    // Accepting a new matrix recalculates every internal node
+    let (mut ts, mut gen_data) = dummy_tree_state::<Hky85>(12);
+    let (p, rates) = (ts.mat, ts.rates.clone());
+    let mv = MatrixMove {
+        scale: 0.5,
+        window: 0.05,
+    };
+    ts = apply_move(ts, mv, always_accept, &mut gen_data);
+    assert_ne!(ts.mat.get_params(), p.get_params());
+    let full = create_internal_data(gen_data.clone(), &ts.top, &ts.mat.get_matrix(), &rates);
+    assert_eq!(full.partials, gen_data.partials);
+    assert!((ts.likelihood - ts.top.likelihood(&full, &ts.mat, &rates)).abs() < 1e-10);
+
+    // Rejecting it keeps the matrix and partials
+    let (old_params, old_partials) = (ts.mat.get_params(), gen_data.partials.clone());
+    let mv = MatrixMove {
+        scale: 0.5,
+        window: 0.05,
+    };
+    ts = apply_move(ts, mv, |_, _| false, &mut gen_data);
+    assert_eq!(ts.mat.get_params(), old_params);
+    assert_eq!(gen_data.partials, old_partials);
+}
+
+#[test]
+fn parameter_optimisation() {
+    let (ts, gen_data) = dummy_tree_state::<Gtr>(5);
+    let (p, ll) = (ts.mat, ts.likelihood);
+    let free = FreeParams {
+        rates: true,
+        freqs: true,
+        alpha: true,
+        branch_lengths: true,
+    };
+
+    // The transform round trips, with one exchangeability fixed
+    let cost = LikelihoodCost::new(ts.clone(), gen_data.clone(), free);
+    let x = cost.params();
+    assert_eq!(x.len(), 5 + 3 + 1 + 8);
+    assert!((cost.log_likelihood(&x) - ll).abs() < 1e-8);
+    let (top, mat, new_rates) = cost.apply(&x);
+    assert!((mat.get_params()[..10].iter())
+        .zip(p.get_params().iter())
+        .all(|(a, b)| (a - b).abs() < 1e-12));
+    assert!((new_rates.get_alpha() - 0.5).abs() < 1e-12);
+    for (a, b) in top.nodes.iter().zip(ts.top.nodes.iter()) {
+        assert!((a.get_branchlen() - b.get_branchlen()).abs() < 1e-12);
+    }
+
+    // Analytic branch length gradients match central differences
+    let gradient = cost.gradient(&x).unwrap();
+    for i in 9..x.len() {
+        let mut shifted = x.clone();
+        shifted[i] = x[i] + 1e-5;
+        let up = cost.log_likelihood(&shifted);
+        shifted[i] = x[i] - 1e-5;
+        let down = cost.log_likelihood(&shifted);
+        let numerical = -(up - down) / 2e-5;
+        assert!((gradient[i] - numerical).abs() < 1e-4 * numerical.abs().max(1.0));
+    }
+
+    // A few iterations never make the starting point worse
+    for optimiser in [Optimiser::Lbfgs, Optimiser::NelderMead] {
+        let (new_ts, new_data) =
+            optimise_parameters(ts.clone(), gen_data.clone(), free, optimiser, 5).unwrap();
+        assert!(new_ts.likelihood >= ll);
+        assert!(new_ts.mat.get_params().iter().all(|r| *r > 0.0));
+        assert!((new_ts.mat.get_freqs().iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(new_ts.rates.get_alpha() > 0.0);
+        let ll = new_ts.top.likelihood(&new_data, &new_ts.mat, &new_ts.rates);
+        assert!((ll - new_ts.likelihood).abs() < 1e-10);
+    }
+}
+
+#[test]
+fn newick_parsing() {
+    let tree = parse_newick(
+        "('taxon one':0.1,'it''s':0.2[&rate=1.5],(C:0.3,D[&x=1],E:0.5)95:0.05)root[&R];",
+    )
+    .unwrap();
+    let labels: Vec<Option<&str>> = tree
+        .leaves()
+        .iter()
+        .map(|l| tree.nodes[*l].label.as_deref())
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            Some("taxon one"),
+            Some("it's"),
+            Some("C"),
+            Some("D"),
+            Some("E")
+        ]
+    );
+    assert_eq!(tree.nodes[tree.root].label.as_deref(), Some("root"));
+    assert_eq!(tree.nodes[tree.root].comments, ["R"]);
+    assert_eq!(tree.nodes[2].comments, ["rate=1.5"]);
+    assert_eq!(tree.nodes[5].length, None);
+    assert_eq!(tree.nodes[3].support(), Some(95.0));
+    assert_eq!(tree.nodes[3].length, Some(0.05));
+
+    // Multifurcations are kept until resolved
+    assert_eq!(tree.nodes[tree.root].children.len(), 3);
+    assert!(!tree.is_binary());
+    let mut resolved = tree.clone();
+    resolved.resolve_multifurcations();
+    assert!(resolved.is_binary());
+    assert_eq!(resolved.nodes.len(), 9);
+    let resolved_labels: Vec<Option<&str>> = resolved
+        .leaves()
+        .iter()
+        .map(|l| resolved.nodes[*l].label.as_deref())
+        .collect();
+    assert_eq!(resolved_labels, labels);
+
+    // Nodes with one child are removed
+    let mut unary = parse_newick("(((A:1,B:2):0.5):0.25,C:3);").unwrap();
+    unary.resolve_multifurcations();
+    assert_eq!(unary.nodes.len(), 5);
+    let internal = unary.nodes[unary.root].children[0];
+    assert_eq!(unary.nodes[internal].length, Some(0.75));
+
+    // Leaves are numbered in the order written, then internal nodes postorder
+    let nodes = from_newick("(A:0.1,B,(C:0.3,D:0.4):0.5,E:0.6);", &[]).unwrap();
+    let t = Topology {
+        nodes,
+        tree_vec: Vec::new(),
+        names: Vec::new(),
+    };
+    assert_eq!(t.count_leaves(), 5);
+    assert_eq!(t.nodes.len(), 9);
+    assert_eq!(t.get_root().get_id(), 8);
+    assert_eq!(
+        t.get_newick(),
+        "(4:0.6,((3:0.4,2:0.3)6:0.5,(1:1,0:0.1)5:0)7:0)8:1;"
+    );
+    for node in t.nodes.iter() {
+        if let Some(p) = node.get_parent() {
+            assert_eq!(node.get_depth(), t.nodes[p].get_depth() + 1);
+        }
+    }
+
+    // Errors give the byte offset of the problem
+    for (newick, offset) in [
+        ("((A,B),C", 0),
+        ("(A,B):x;", 6),
+        ("(A,B));", 5),
+        ("(A,'B);", 3),
+        ("(A,B[x);", 4),
+        ("(A,B);C", 6),
+        ("(A B,C);", 3),
+        ("", 0),
+    ] {
+        assert_eq!(
+            parse_newick(newick).unwrap_err().offset,
+            offset,
+            "{}",
+            newick
+        );
+    }
+}
+
+#[test]
+fn taxon_names() {
+    // Record IDs are read up to the first space
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("bactrees_names.fasta");
+    let fasta = ">taxA first\nACGT\n>B(1)\nACGA\n>it's\nACTT\n>D\nAGGT\n>E_2\nCCGT\n";
+    std::fs::write(&path, fasta).unwrap();
+    let (names, seqs) = read_alignment(path.to_str().unwrap());
+    assert_eq!(names, ["taxA", "B(1)", "it's", "D", "E_2"]);
+    assert_eq!(seqs[2], b"ACTT");
+
+    // Leaves are written by name, quoted where needed
+    let mut t = from_vec(&random_vector(5));
+    t.names = names.clone();
+    let newick = t.get_newick();
+    for label in ["taxA:", "'B(1)':", "'it''s':", "D:", "E_2:"] {
+        assert!(newick.contains(label), "{}", newick);
+    }
+    let mut unnamed = t.clone();
+    unnamed.names = Vec::new();
+    assert!(unnamed.get_newick().contains("4:"));
+
+    // Reading the tree back matches each leaf to its row, so the likelihood is the same
+    let p = Hky85::default();
+    let rates = SiteRates::default();
+    let gen_data = create_dummy_gendata(50, &t, &p.get_matrix(), &rates);
+    let top = Topology {
+        nodes: from_newick(&newick, &names).unwrap(),
+        tree_vec: Vec::new(),
+        names: names.clone(),
+    };
+    assert_eq!(top.get_newick().len(), newick.len());
+    let gen_data_2 = create_internal_data(gen_data.clone(), &top, &p.get_matrix(), &rates);
+    assert!(
+        (t.likelihood(&gen_data, &p, &rates) - top.likelihood(&gen_data_2, &p, &rates)).abs()
+            < 1e-10
+    );
+
+    // Trees must have every taxon of the alignment exactly once
+    let newick = "((taxA,'B(1)'),('it''s',(D,F)));";
+    let err = from_newick(newick, &names).unwrap_err();
+    assert_eq!(err.offset, newick.find('F').unwrap());
+    assert!(err.message.contains("'F'"));
+    let err = from_newick("((taxA,'B(1)'),('it''s',(D,D)));", &names).unwrap_err();
+    assert!(err.message.contains("more than once"));
+    let err = from_newick("((taxA,'B(1)'),('it''s',D));", &names).unwrap_err();
+    assert!(err.message.contains("'E_2'"));
+}
+
+#[test]
+fn starting_tree_from_newick() {
+    let names: Vec<String> = (0..8).map(|i| format!("taxon_{}", i)).collect();
+    let (mut ts, gen_data) = dummy_tree_state::<Hky85>(8);
+    let (p, rates) = (ts.mat, ts.rates.clone());
+    ts.top.names = names.clone();
+    let mut rng = rand::thread_rng();
+    for node in ts.top.nodes.iter_mut() {
+        node.set_branchlen(rng.gen_range(0.01..0.5));
+    }
+    let gen_data = create_internal_data(gen_data, &ts.top, &p.get_matrix(), &rates);
+    let ll = ts.top.likelihood(&gen_data, &p, &rates);
+
+    // The tree read back has the same likelihood, and a tree vector moves can use
+    let top = topology_from_newick(&ts.top.get_newick(), &names).unwrap();
+    assert_eq!(top.names, names);
+    let mut gen_data = create_internal_data(gen_data, &top, &p.get_matrix(), &rates);
+    assert!((top.likelihood(&gen_data, &p, &rates) - ll).abs() < 1e-10);
+
+    ts = TreeState {
+        top,
+        mat: p,
+        rates: rates.clone(),
+        likelihood: ll,
+    };
+    ts = apply_move(ts, PeturbVec { n: 3 }, always_accept, &mut gen_data);
+    assert_eq!(ts.top.names, names);
+    let full = create_internal_data(gen_data.clone(), &ts.top, &p.get_matrix(), &rates);
+    assert!((ts.likelihood - ts.top.likelihood(&full, &p, &rates)).abs() < 1e-8);
+
+    // Multifurcations and missing lengths are allowed, in any leaf order
+    let newick = "(taxon_3,taxon_0,(taxon_1,taxon_7,taxon_2):0.2,(taxon_6,(taxon_5,taxon_4)));";
+    let top = topology_from_newick(newick, &names).unwrap();
+    assert_eq!(top.count_leaves(), 8);
+    assert_eq!(top.tree_vec.len(), 8);
+    assert!(top.get_newick().contains("taxon_2:1"));
+}
+
+// Length of the path between every pair of leaves
+fn leaf_distances(top: &Topology) -> ndarray::Array2<f64> {
+    let n = top.count_leaves();
+    let to_root = |leaf: usize| {
+        let mut path = HashMap::new();
+        let (mut node, mut length) = (leaf, 0.0);
+        path.insert(node, 0.0);
+        while let Some(parent) = top.nodes[node].get_parent() {
+            length += top.nodes[node].get_branchlen();
+            node = parent;
+            path.insert(node, length);
+        }
+        path
+    };
+    let paths: Vec<HashMap<usize, f64>> = (0..n).map(to_root).collect();
+    ndarray::Array2::from_shape_fn((n, n), |(i, j)| {
+        paths[i]
+            .iter()
+            .filter_map(|(node, d)| paths[j].get(node).map(|e| d + e))
+            .fold(f64::INFINITY, f64::min)
+    })
+}
+
+#[test]
+fn distance_trees() {
+    let a = b"AAAAAAAAAAAA".to_vec();
+    let transition = b"AAAAAAAAAGN-".to_vec();
+    let transversion = b"AAAAAAAAACAA".to_vec();
+    assert!((pairwise_distance(&a, &transition, DistanceMethod::PDistance) - 0.1).abs() < 1e-12);
+    let jc = -0.75 * (1.0 - 0.4_f64 / 3.0).ln();
+    assert!((pairwise_distance(&a, &transition, DistanceMethod::Jc69) - jc).abs() < 1e-12);
+    let k2p = |p: f64, q: f64| -0.5 * (1.0 - 2.0 * p - q).ln() - 0.25 * (1.0 - 2.0 * q).ln();
+    assert!(
+        (pairwise_distance(&a, &transition, DistanceMethod::K2p) - k2p(0.1, 0.0)).abs() < 1e-12
+    );
+    let q = 1.0 / 12.0;
+    assert!(
+        (pairwise_distance(&a, &transversion, DistanceMethod::K2p) - k2p(0.0, q)).abs() < 1e-12
+    );
+    assert_eq!(pairwise_distance(&a, &a, DistanceMethod::Jc69), 0.0);
+    assert_eq!(
+        pairwise_distance(b"ACGT", b"CATG", DistanceMethod::Jc69),
+        MAX_BRANCH_LEN
+    );
+    let dist = distance_matrix(
+        &[a.clone(), transition, transversion],
+        DistanceMethod::PDistance,
+    );
+    assert_eq!(dist, dist.t());
+    assert_eq!(dist[[0, 1]], 0.1);
+
+    // Both methods recover a tree from its own path lengths
+    let names: Vec<String> = (0..10).map(|i| format!("s{}", i)).collect();
+    let mut t = from_vec(&random_vector(10));
+    let mut rng = rand::thread_rng();
+    for node in t.nodes.iter_mut() {
+        node.set_branchlen(rng.gen_range(0.01..0.3));
+    }
+    let dist = leaf_distances(&t);
+    for bionj in [false, true] {
+        let top = neighbour_joining(&dist, &names, bionj).unwrap();
+        assert_eq!(top.names, names);
+        let nj_dist = leaf_distances(&top);
+        assert!(
+            nj_dist
+                .iter()
+                .zip(dist.iter())
+                .all(|(a, b)| (a - b).abs() < 1e-8),
+            "{}",
+            top.get_newick()
+        );
+        let from_tree_vec = from_vec(&top.tree_vec);
+        for (a, b) in top.nodes.iter().zip(from_tree_vec.nodes.iter()) {
+            assert_eq!(a.get_parent(), b.get_parent());
+        }
+    }
+    let two = neighbour_joining(&dist.slice(s![..2, ..2]).to_owned(), &names[..2], true).unwrap();
+    assert!((two.nodes[0].get_branchlen() - 0.5 * dist[[0, 1]]).abs() < 1e-12);
+}
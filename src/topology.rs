@@ -1,6 +1,10 @@
-use crate::base_freq_logse;
+use crate::genetic_data::{ascertainment_correction, pattern_likelihoods, Ascertainment};
+use crate::newick::{parse_newick, quote_label, NewickError, NewickTree};
 use crate::newick_to_vec::newick_to_vector;
-use crate::BF_DEFAULT;
+use crate::rate_matrix::RateMatrix;
+use crate::root_likelihood;
+use crate::site_rates::SiteRates;
+use crate::GeneticData;
 use ndarray::s;
 use ndarray::Array2;
 use std::collections::HashMap;
@@ -80,10 +84,12 @@ impl PartialEq for NodeTuple {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Topology {
     pub nodes: Vec<NodeTuple>,
     pub tree_vec: Vec<usize>,
+    // Taxon name of each leaf, in alignment order. Leaves are written by ID if empty
+    pub names: Vec<String>,
 }
 
 // Builds a vector of NodeTuples from an integer tree vector
@@ -150,129 +156,198 @@ pub fn from_vec(tree_vec: &[usize]) -> Topology {
     Topology {
         nodes,
         tree_vec: tree_vec.to_vec(),
+        names: Vec::new(),
     }
 }
 
-// Builds a new Topology from a Newick String
-pub fn from_newick(rjstr: String) -> Vec<NodeTuple> {
-    let mut new_str: String = rjstr.clone();
-    let full_split: Vec<&str> = rjstr
-        .split(['(', ',', ')', ';'])
-        .filter(|c| !c.is_empty())
-        .collect();
-    let mut label_dictionary: HashMap<usize, String> = HashMap::with_capacity(full_split.len());
-    let mut branch_length: HashMap<usize, f64> = HashMap::with_capacity(full_split.len());
-    let mut bl: f64;
-    let mut leaf_idx: usize = 0;
-    let mut internal_idx: usize = ((full_split.len() + 1) / 2) + 1;
-    let mut idx: usize;
-
-    for (i, x) in full_split.iter().enumerate() {
-        // Split this node into (possibly non-existant) label and branch length
-        let bits: Vec<&str> = x.split(':').filter(|c| !c.is_empty()).collect();
-
-        // Depending on size of split vector we know if we have a labelled (leaf) node or not
-        if bits.len().eq(&2) {
-            // If 2 parts we have a label and a length
-            idx = leaf_idx;
+// Builds the nodes of a Topology from a Newick String. Multifurcations are resolved
+// with branches of length zero, and missing branch lengths are set to 1. Leaves are
+// given the row of their name in names, or numbered from zero in the order they appear
+// if names is empty. Internal nodes are numbered after the leaves postorder, so the
+// root is last
+pub fn from_newick(newick: &str, names: &[String]) -> Result<Vec<NodeTuple>, NewickError> {
+    let mut tree = parse_newick(newick)?;
+    tree.resolve_multifurcations();
+    if tree.nodes.len() < 3 {
+        return Err(NewickError {
+            offset: 0,
+            message: String::from("Tree has fewer than two leaves"),
+        });
+    }
+
+    let postorder = tree.postorder();
+    let n_leaves = tree.leaves().len();
+    let leaf_ids = match names.is_empty() {
+        true => (0..n_leaves).collect(),
+        false => match_leaves(&tree, names)?,
+    };
+    let mut ids = vec![0; tree.nodes.len()];
+    let (mut leaf_idx, mut internal_idx) = (0, n_leaves);
+    for node in postorder.iter() {
+        if tree.nodes[*node].is_leaf() {
+            ids[*node] = leaf_ids[leaf_idx];
             leaf_idx += 1;
-            label_dictionary.insert(idx, bits.first().unwrap().to_string());
         } else {
-            // If 1 part we assign an internal label
-            idx = internal_idx;
+            ids[*node] = internal_idx;
             internal_idx += 1;
         }
+    }
+    // Postorder visits leaves in the order they are written
+    let mut nodevec: Vec<NodeTuple> =
+        vec![NodeTuple(0, None, None, None, 0.0, 0); tree.nodes.len()];
+    for node in postorder.iter().rev() {
+        let n = &tree.nodes[*node];
+        let parent = n.parent.map(|p| ids[p]);
+        let dpth = parent.map_or(0, |p| nodevec[p].get_depth() + 1);
+        nodevec[ids[*node]] = NodeTuple(
+            ids[*node],
+            parent,
+            n.children.first().map(|c| ids[*c]),
+            n.children.get(1).map(|c| ids[*c]),
+            n.length.unwrap_or(1.0),
+            dpth,
+        );
+    }
 
-        // Save branch length
-        bl = bits.last().unwrap().parse().unwrap();
-        branch_length.insert(i, bl);
-
-        // Put new label into new string and replace branch length
-        new_str = new_str.replace(x, &idx.to_string());
-    }
-
-    // This section tries to solve the polytomy at the root of rapidNJ trees
-    // By splicing in some brackets and naming more internal nodes
-    // Add first end bracket before last comma in string
-    let firstcom = new_str.rfind(',').unwrap();
-    new_str.insert(firstcom, ')');
-    // Give an internal node label after this new bracket
-    let mut nstr: String = [
-        &new_str[0..=firstcom],
-        &internal_idx.to_string(),
-        &new_str[firstcom + 1..new_str.len()],
-    ]
-    .join("");
-    internal_idx += 1;
-    // Find last closing bracket in string
-    let firstbrack = nstr.rfind(')').unwrap();
-    // Add root node label
-    nstr = [&nstr[0..=firstbrack], &internal_idx.to_string(), &";"].join("");
-    // Add corresponding opening bracket to start of string
-    nstr.insert(0, '(');
-
-    // This section goes through the Newick string and records the parent nodes of each node
-    // so that we can build a Tree struct
-    let mut current_parent: Option<usize> = None;
-    let mut parent_vector: Vec<Option<usize>> = vec![None; internal_idx + 1];
-    let mut idx: Option<usize> = Some(internal_idx);
-
-    for i in (1..nstr.len()).rev() {
-        let ch: char = nstr.chars().nth(i).unwrap();
-
-        if ch.eq(&')') || ch.eq(&',') {
-            if ch.eq(&')') {
-                current_parent = idx;
-            }
+    Ok(nodevec)
+}
 
-            let mut j = i - 1;
-            let mut jch: char = nstr.chars().nth(j).unwrap();
-            while j > 0 && jch.ne(&'(') && jch.ne(&',') && jch.ne(&')') {
-                j -= 1;
-                jch = nstr.chars().nth(j).unwrap();
-            }
+// Builds a Topology from a Newick String, with leaves matched to names as in
+// from_newick
+pub fn topology_from_newick(newick: &str, names: &[String]) -> Result<Topology, NewickError> {
+    topology_from_nodes(from_newick(newick, names)?, names)
+}
 
-            if j != (i - 1) {
-                let leaf: &str = &nstr[(j + 1)..i];
-                idx = Some(leaf.parse().unwrap());
-                parent_vector[idx.unwrap()] = current_parent;
+// Builds a Topology from the nodes of a binary tree, with leaf i named names[i]. The
+// nodes are renumbered to follow the tree vector, so moves can be made from it, and
+// branch lengths are copied across by the leaves below each node
+pub fn topology_from_nodes(
+    nodes: Vec<NodeTuple>,
+    names: &[String],
+) -> Result<Topology, NewickError> {
+    let parsed = Topology {
+        nodes,
+        tree_vec: Vec::new(),
+        names: Vec::new(),
+    };
+    let tree_vec = newick_to_vector(&parsed.get_newick(), parsed.count_leaves());
+    let mut top = from_vec(&tree_vec);
+    top.names = names.to_vec();
+
+    let new_clades = clades(&top);
+    for (leaves, id) in clades(&parsed) {
+        let Some(new_id) = new_clades.get(&leaves) else {
+            return Err(NewickError {
+                offset: 0,
+                message: String::from("Tree changed when converted to a vector"),
+            });
+        };
+        top.nodes[*new_id].set_branchlen(parsed.nodes[id].get_branchlen());
+    }
+    Ok(top)
+}
+
+// Each node keyed by the sorted IDs of the leaves below it
+fn clades(top: &Topology) -> HashMap<Vec<usize>, usize> {
+    let mut below: HashMap<usize, Vec<usize>> = HashMap::new();
+    for node in top.postorder(top.get_root()) {
+        let leaves = match (node.get_lchild(), node.get_rchild()) {
+            (Some(l), Some(r)) => {
+                let mut leaves = [below[&l].as_slice(), below[&r].as_slice()].concat();
+                leaves.sort();
+                leaves
             }
-        } else if ch.eq(&'(') {
-            current_parent = parent_vector[current_parent.unwrap()];
-        }
+            _ => vec![node.get_id()],
+        };
+        below.insert(node.get_id(), leaves);
     }
+    below.into_iter().map(|(id, leaves)| (leaves, id)).collect()
+}
 
-    // Add nodes to Tree from parent vector, give correct branch length
-    let mut nodevec: Vec<NodeTuple> =
-        vec![NodeTuple(0, None, None, None, 0.0, 0); internal_idx + 1];
-
-    for (i, parent) in parent_vector.iter().enumerate().rev() {
-        let mut dpth = 0;
-        if let Some(par) = parent {
-            // Add this child to existing parent
-            nodevec[*par].add_child(Some(i));
-            // Get child depth from parent + 1
-            dpth = nodevec[*par].get_depth() + 1;
+// Row in names of each leaf of tree, in the order the leaves are written. Every name
+// must be the label of exactly one leaf
+fn match_leaves(tree: &NewickTree, names: &[String]) -> Result<Vec<usize>, NewickError> {
+    let error = |offset: usize, message: String| Err(NewickError { offset, message });
+    let mut rows: HashMap<&str, usize> = HashMap::with_capacity(names.len());
+    for (i, name) in names.iter().enumerate() {
+        if rows.insert(name.as_str(), i).is_some() {
+            return error(
+                0,
+                format!("Taxon '{}' is in the alignment more than once", name),
+            );
         }
-        // Update this child NodeTuple
-        nodevec[i].set_parent(*parent);
-        nodevec[i].set_depth(dpth);
     }
 
-    nodevec
+    let mut found = vec![false; names.len()];
+    let mut leaf_ids = Vec::with_capacity(names.len());
+    for node in tree.postorder() {
+        let n = &tree.nodes[node];
+        if !n.is_leaf() {
+            continue;
+        }
+        let Some(label) = &n.label else {
+            return error(n.offset, String::from("Leaf without a name"));
+        };
+        let Some(row) = rows.get(label.as_str()) else {
+            return error(
+                n.offset,
+                format!("Taxon '{}' is not in the alignment", label),
+            );
+        };
+        if found[*row] {
+            return error(
+                n.offset,
+                format!("Taxon '{}' is in the tree more than once", label),
+            );
+        }
+        found[*row] = true;
+        leaf_ids.push(*row);
+    }
+    if let Some(missing) = found.iter().position(|f| !f) {
+        return error(
+            0,
+            format!(
+                "Taxon '{}' from the alignment is not in the tree",
+                names[missing]
+            ),
+        );
+    }
+    Ok(leaf_ids)
 }
 
 impl Topology {
     // Builds a Newick String for a Topology object
     pub fn get_newick(&self) -> String {
+        self.newick_string(|node| node.get_branchlen().to_string())
+    }
+
+    // Newick String with the number of substitutions on each branch in a comment
+    // after its length, as in 3:0.1[&snps=2]. Nodes missing from snp_counts have none
+    pub fn get_newick_snps(&self, snp_counts: &HashMap<usize, usize>) -> String {
+        self.newick_string(|node| {
+            let snps = snp_counts.get(&node.get_id()).copied().unwrap_or(0);
+            format!("{}[&snps={}]", node.get_branchlen(), snps)
+        })
+    }
+
+    // Name of a leaf, or the ID of an internal node or when there are no names
+    fn node_label(&self, node: &NodeTuple) -> String {
+        match self.names.get(node.get_id()) {
+            Some(name) if node.get_lchild().is_none() => quote_label(name),
+            _ => node.get_id().to_string(),
+        }
+    }
+
+    // Builds the Newick String, with branch giving the text after each colon
+    fn newick_string<F: Fn(&NodeTuple) -> String>(&self, branch: F) -> String {
         let mut current_node: Option<&NodeTuple> = Some(self.get_root());
         let mut next_node: Option<&NodeTuple>;
         let mut return_nodes: Vec<Option<&NodeTuple>> = Vec::new();
         let mut newick: Vec<String> = vec![
             String::from(";"),
-            current_node.unwrap().get_branchlen().to_string(),
+            branch(current_node.unwrap()),
             String::from(":"),
-            current_node.unwrap().get_id().to_string(),
+            self.node_label(current_node.unwrap()),
         ];
 
         while current_node.is_some() {
@@ -284,9 +359,9 @@ impl Topology {
                     next_node = self.nodes.get(a);
 
                     newick.push(String::from(")"));
-                    newick.push(next_node.unwrap().get_branchlen().to_string());
+                    newick.push(branch(next_node.unwrap()));
                     newick.push(String::from(":"));
-                    newick.push(next_node.unwrap().get_id().to_string());
+                    newick.push(self.node_label(next_node.unwrap()));
                 }
                 (Some(a), Some(b)) => {
                     next_node = self.nodes.get(a);
@@ -294,9 +369,9 @@ impl Topology {
                     return_nodes.push(self.nodes.get(b));
 
                     newick.push(String::from(")"));
-                    newick.push(next_node.unwrap().get_branchlen().to_string());
+                    newick.push(branch(next_node.unwrap()));
                     newick.push(String::from(":"));
-                    newick.push(next_node.unwrap().get_id().to_string());
+                    newick.push(self.node_label(next_node.unwrap()));
                 }
                 (None, _) => {
                     next_node = match return_nodes.pop() {
@@ -318,9 +393,9 @@ impl Topology {
                             }
                         }
 
-                        newick.push(next_node.unwrap().get_branchlen().to_string());
+                        newick.push(branch(next_node.unwrap()));
                         newick.push(String::from(":"));
-                        newick.push(next_node.unwrap().get_id().to_string());
+                        newick.push(self.node_label(next_node.unwrap()));
                     } else {
                         let n: usize = current_node.unwrap().get_depth();
                         for _ in 1..=n {
@@ -374,15 +449,41 @@ impl Topology {
             .unwrap()
     }
 
-    pub fn likelihood(
+    pub fn likelihood<R: RateMatrix>(
         &self,
-        gen_data: &ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 3]>>,
+        gen_data: &GeneticData,
+        rate_matrix: &R,
+        site_rates: &SiteRates,
     ) -> f64 {
-        let root_likelihood = gen_data.slice(s![self.get_root().get_id(), .., ..]);
+        root_likelihood(
+            gen_data.log_partials(self.get_root().get_id()).view(),
+            gen_data,
+            rate_matrix.get_freqs(),
+            site_rates,
+        )
+    }
 
-        root_likelihood
-            .rows()
-            .into_iter()
-            .fold(0.0, |acc, base| acc + base_freq_logse(base, BF_DEFAULT))
+    // Log-likelihood of each alignment column, in the original order. With the Lewis
+    // correction each site is conditioned on being variable, so these sum to the
+    // likelihood. The other corrections add terms for removed sites, which are not
+    // included
+    pub fn site_likelihoods<R: RateMatrix>(
+        &self,
+        gen_data: &GeneticData,
+        rate_matrix: &R,
+        site_rates: &SiteRates,
+    ) -> Vec<f64> {
+        let mut pattern_ll = pattern_likelihoods(
+            gen_data.log_partials(self.get_root().get_id()).view(),
+            gen_data,
+            rate_matrix.get_freqs(),
+            site_rates,
+        );
+        if gen_data.ascertainment == Ascertainment::Lewis {
+            let n_sites: f64 = gen_data.weights.iter().sum();
+            let correction = ascertainment_correction(&pattern_ll, gen_data) / n_sites;
+            pattern_ll.iter_mut().for_each(|ll| *ll += correction);
+        }
+        gen_data.expand_patterns(&pattern_ll)
     }
 }
@@ -1,20 +1,24 @@
+use crate::genetic_data::{node_log_partials, node_partials, NodePartials};
 use crate::iterators::ChangeIter;
 use crate::rate_matrix;
+use crate::site_rates::SiteRates;
 use crate::topology;
 use crate::topology::from_vec;
 use crate::topology::NodeTuple;
 use crate::RateMatrix;
 use crate::Topology;
 use crate::TreeMove;
-use crate::{base_freq_logse, matrix_exp, node_likelihood, BF_DEFAULT};
+use crate::{root_likelihood, transition_matrices, GeneticData};
 use std::collections::HashMap;
 use std::hash::Hash;
 // use crate::ExactMove;
-use ndarray::s;
+use ndarray::Array1;
 
+#[derive(Clone)]
 pub struct TreeState<R: RateMatrix> {
     pub top: Topology,
     pub mat: R,
+    pub rates: SiteRates,
     pub likelihood: f64,
 }
 
@@ -22,79 +26,102 @@ pub fn apply_move<M: TreeMove<R>, R: RateMatrix>(
     current_ts: TreeState<R>,
     move_fn: M,
     accept_fn: fn(&f64, &f64) -> bool,
-    gen_data: &mut ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 3]>>,
+    gen_data: &mut GeneticData,
 ) -> TreeState<R> {
     let (new_topology, new_mat, changes) = move_fn.generate(&current_ts);
 
+    // A new rate matrix changes the partials at every internal node, whether or not
+    // the topology changed
+    let changes = match new_mat {
+        Some(_) => {
+            let top = new_topology.as_ref().unwrap_or(&current_ts.top);
+            Some(
+                top.postorder_notips(top.get_root())
+                    .map(|n| n.get_id())
+                    .collect(),
+            )
+        }
+        None => changes,
+    };
+
     if changes.is_none() {
         return current_ts;
     }
 
-    let rate_matrix = match new_mat {
-        Some(x) => x.get_matrix(),
-        None => current_ts.mat.get_matrix(),
+    let candidate_mat = match new_mat {
+        Some(x) => x,
+        None => current_ts.mat,
     };
+    let rate_matrix = candidate_mat.get_matrix();
 
-    let candidate_top = match new_topology {
-        Some(t) => t,
-        None => current_ts.top,
-    };
+    // Kept separate from the current topology, which is returned if the move is rejected
+    let candidate_top = new_topology.as_ref().unwrap_or(&current_ts.top);
 
     let nodes_to_update = candidate_top.changes_iter_notips(changes.unwrap());
 
-    let mut temp_likelihoods: HashMap<
-        usize,
-        ndarray::ArrayBase<ndarray::OwnedRepr<f64>, ndarray::Dim<[usize; 2]>>,
-    > = HashMap::new();
+    // Partials and scalers of the recalculated nodes
+    let mut temp_likelihoods: HashMap<usize, (NodePartials, Array1<i32>)> = HashMap::new();
 
     for node in nodes_to_update {
         let (lchild, rchild) = (node.get_lchild().unwrap(), node.get_rchild().unwrap());
 
-        let seql = match temp_likelihoods.contains_key(&lchild) {
-            true => temp_likelihoods.get(&lchild).unwrap().slice(s![.., ..]),
-            false => gen_data.slice(s![lchild, .., ..]),
+        let seql = match temp_likelihoods.get(&lchild) {
+            Some((partials, scalers)) => (partials.view(), scalers.view()),
+            None => (gen_data.node_view(lchild), gen_data.node_scalers(lchild)),
         };
-        let seqr = match temp_likelihoods.contains_key(&rchild) {
-            true => temp_likelihoods.get(&rchild).unwrap().slice(s![.., ..]),
-            false => gen_data.slice(s![rchild, .., ..]),
+        let seqr = match temp_likelihoods.get(&rchild) {
+            Some((partials, scalers)) => (partials.view(), scalers.view()),
+            None => (gen_data.node_view(rchild), gen_data.node_scalers(rchild)),
         };
 
-        let node_ll = node_likelihood(
+        let node_ll = node_partials(
+            gen_data.kernel,
+            gen_data.pool.as_deref(),
             seql,
             seqr,
-            &matrix_exp(&rate_matrix, candidate_top.nodes[lchild].get_branchlen()),
-            &matrix_exp(&rate_matrix, candidate_top.nodes[rchild].get_branchlen()),
+            &transition_matrices(
+                &rate_matrix,
+                candidate_top.nodes[lchild].get_branchlen(),
+                &current_ts.rates,
+            ),
+            &transition_matrices(
+                &rate_matrix,
+                candidate_top.nodes[rchild].get_branchlen(),
+                &current_ts.rates,
+            ),
         );
 
         temp_likelihoods.insert(node.get_id(), node_ll);
     }
 
     // Calculate whole new topology likelihood at root
-    let new_ll = temp_likelihoods
+    let (root_partials, root_scalers) = temp_likelihoods
         .get(&candidate_top.get_root().get_id())
-        .unwrap()
-        .rows()
-        .into_iter()
-        .fold(0.0, |acc, base| acc + base_freq_logse(base, BF_DEFAULT));
+        .unwrap();
+    let root_ll = node_log_partials(gen_data.kernel, root_partials.view(), root_scalers.view());
+    let new_ll = root_likelihood(
+        root_ll.view(),
+        gen_data,
+        candidate_mat.get_freqs(),
+        &current_ts.rates,
+    );
 
     if accept_fn(&current_ts.likelihood, &new_ll) {
         // Drain hashmap into gen_data
-        for (i, ll_data) in temp_likelihoods.drain() {
-            gen_data.slice_mut(s![i, .., ..]).assign(&ll_data);
+        for (i, (ll_data, scalers)) in temp_likelihoods.drain() {
+            gen_data.set_node(i, &ll_data, &scalers);
         }
-        let nm = match new_mat {
-            Some(x) => x,
-            None => current_ts.mat,
-        };
         TreeState {
-            top: candidate_top,
-            mat: nm,
+            top: new_topology.unwrap_or(current_ts.top),
+            mat: candidate_mat,
+            rates: current_ts.rates,
             likelihood: new_ll,
         }
     } else {
         TreeState {
-            top: candidate_top,
+            top: current_ts.top,
             mat: current_ts.mat,
+            rates: current_ts.rates,
             likelihood: current_ts.likelihood,
         }
     }
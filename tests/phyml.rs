@@ -65,4 +65,73 @@ fn jc69_likelihood() {
 
     // Epsilon might be too strict here
     assert_approx_eq!(f64, likelihood, phyml_likelihood, epsilon = 0.0000001)
-}
\ No newline at end of file
+}
+
+// Runs bactrees without optimisation, then phyml on the output tree with the same model,
+// returning the two log-likelihoods
+fn compare_with_phyml(bactrees_args: &[&str], phyml_args: &[&str]) -> (f64, f64) {
+    let sandbox = TestSetup::setup();
+    let input_alignment_fasta = sandbox.file_string("listeria0.aln", TestDir::Input);
+    let input_alignment_phylip = sandbox.file_string("listeria0.phylip", TestDir::Input);
+
+    let output = Command::new(cargo_bin("bactrees"))
+        .current_dir(sandbox.get_wd())
+        .arg("-a")
+        .arg(input_alignment_fasta.as_str())
+        .arg("--no-optimise")
+        .args(bactrees_args)
+        .output()
+        .unwrap()
+        .stdout;
+    let output_string = String::from_utf8(output).unwrap();
+    let output_parts: Vec<&str> = output_string.split("\n").collect();
+    let likelihood: f64 = output_parts[0].parse().unwrap();
+
+    let mut output_tr_file = sandbox.create_file("tree.nwk").unwrap();
+    // Remove the quotes
+    let mut tree_string = output_parts[1].to_string();
+    tree_string.pop();
+    tree_string.remove(0);
+    writeln!(output_tr_file.0, "{tree_string}").unwrap();
+
+    // phyml -i <path_to_sequence> -u <path to newick tree> -o n <model args>
+    let mut phyml_likelihood: f64 = 0.0;
+    let phyml_out = Command::new("phyml")
+        .current_dir(sandbox.get_wd())
+        .args(["-i", input_alignment_phylip.as_str(), "-u", output_tr_file.1.as_str(), "-o", "n"])
+        .args(phyml_args)
+        .output()
+        .unwrap()
+        .stdout;
+    let phyml_stdout = String::from_utf8(phyml_out).unwrap();
+
+    let re = Regex::new(r"^\. Log likelihood of the current tree: (.+)\.$").unwrap();
+    for phyml_outline in phyml_stdout.split("\n") {
+        if let Some(caps) = re.captures(phyml_outline) {
+            phyml_likelihood = caps.get(1).map_or("", |m| m.as_str()).parse().unwrap();
+        }
+    }
+
+    (likelihood, phyml_likelihood)
+}
+
+#[test]
+fn jc69_gamma_likelihood() {
+    let (likelihood, phyml_likelihood) = compare_with_phyml(
+        &["--gamma-cats", "4", "--alpha", "0.5"],
+        &["-m", "JC69", "-a", "0.5", "-c", "4"],
+    );
+
+    // phyml computes the category rates with its own gamma quantile routine
+    assert_approx_eq!(f64, likelihood, phyml_likelihood, epsilon = 0.001)
+}
+
+#[test]
+fn jc69_gamma_invariant_likelihood() {
+    let (likelihood, phyml_likelihood) = compare_with_phyml(
+        &["--gamma-cats", "4", "--alpha", "0.5", "--pinv", "0.2"],
+        &["-m", "JC69", "-a", "0.5", "-c", "4", "-v", "0.2"],
+    );
+
+    assert_approx_eq!(f64, likelihood, phyml_likelihood, epsilon = 0.001)
+}